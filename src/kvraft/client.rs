@@ -1,6 +1,13 @@
 use super::msg::*;
-use madsim::{net, time::*};
-use std::net::SocketAddr;
+use madsim::{
+    net,
+    rand::{self, Rng},
+    time::*,
+};
+use std::{
+    net::SocketAddr,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
 
 pub struct Clerk {
     core: ClerkCore<Op, String>,
@@ -31,6 +38,13 @@ impl Clerk {
 
 pub struct ClerkCore<Req, Rsp> {
     servers: Vec<SocketAddr>,
+    /// A random id to identify this clerk.
+    id: u64,
+    /// The sequence number of the next request.
+    seq: AtomicU64,
+    /// The server that served the last request. It is likely to be the
+    /// leader, but any server forwards requests to the leader it knows.
+    leader: AtomicUsize,
     _mark: std::marker::PhantomData<(Req, Rsp)>,
 }
 
@@ -42,22 +56,43 @@ where
     pub fn new(servers: Vec<SocketAddr>) -> Self {
         ClerkCore {
             servers,
+            id: rand::rng().gen(),
+            seq: AtomicU64::new(0),
+            leader: AtomicUsize::new(0),
             _mark: std::marker::PhantomData,
         }
     }
 
     pub async fn call(&self, args: Req) -> Rsp {
         let net = net::NetLocalHandle::current();
-        for i in 0..self.servers.len() {
-            let ret = net
-                .call_timeout::<Req, Result<Rsp, Error>>(
-                    self.servers[i],
-                    args.clone(),
-                    Duration::from_millis(500),
-                )
-                .await;
-            todo!("handle RPC results");
+        let args = Request {
+            client: self.id,
+            seq: self.seq.fetch_add(1, Ordering::Relaxed),
+            cmd: args,
+        };
+        let mut i = self.leader.load(Ordering::Relaxed);
+        loop {
+            for _ in 0..self.servers.len() {
+                let ret = net
+                    .call_timeout::<Request<Req>, Result<Rsp, Error>>(
+                        self.servers[i],
+                        args.clone(),
+                        Duration::from_millis(500),
+                    )
+                    .await;
+                match ret {
+                    Ok(Ok(rsp)) => {
+                        self.leader.store(i, Ordering::Relaxed);
+                        return rsp;
+                    }
+                    Ok(Err(Error::NotLeader { hint: Some(hint) })) if hint < self.servers.len() => {
+                        i = hint
+                    }
+                    _ => i = (i + 1) % self.servers.len(),
+                }
+            }
+            // no leader is found, wait for an election
+            sleep(Duration::from_millis(100)).await;
         }
-        todo!("handle RPC results");
     }
 }
@@ -7,10 +7,20 @@ pub enum Op {
     Append { key: String, value: String },
 }
 
+/// A command sent by a clerk.
+///
+/// Each clerk numbers its commands so that servers can detect duplicates.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Request<T> {
+    pub client: u64,
+    pub seq: u64,
+    pub cmd: T,
+}
+
 #[derive(thiserror::Error, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Error {
-    #[error("not leader, hint: {hint}")]
-    NotLeader { hint: usize },
+    #[error("not leader, hint: {hint:?}")]
+    NotLeader { hint: Option<usize> },
     #[error("timeout")]
     Timeout,
     #[error("failed to reach consensus")]
@@ -1,24 +1,54 @@
 use super::msg::*;
 use crate::raft;
-use madsim::net;
+use futures::{channel::oneshot, StreamExt};
+use madsim::{net, task, time::*};
 use serde::{Deserialize, Serialize};
 use std::{
+    collections::BTreeMap,
     fmt::{self, Debug},
-    marker::PhantomData,
     net::SocketAddr,
-    sync::Arc,
+    sync::{Arc, Mutex},
 };
 
+/// The time a server waits for a command to be applied.
+const APPLY_TIMEOUT: Duration = Duration::from_millis(500);
+
 pub trait State: net::Message + Default {
     type Command: net::Message + Clone;
     type Output: net::Message;
     fn apply(&mut self, cmd: Self::Command) -> Self::Output;
+
+    /// Apply a batch of commands in order, returning their outputs.
+    ///
+    /// Override it to amortize work across commands.
+    fn apply_batch(&mut self, cmds: Vec<Self::Command>) -> Vec<Self::Output> {
+        cmds.into_iter().map(|cmd| self.apply(cmd)).collect()
+    }
+
+    /// Whether the command does not modify the state.
+    ///
+    /// Read-only commands are served from the local state after a ReadIndex,
+    /// without appending them to the log.
+    fn is_read_only(_cmd: &Self::Command) -> bool {
+        false
+    }
 }
 
 pub struct Server<S: State> {
-    rf: raft::RaftHandle,
+    rf: raft::typed::RaftHandle<Request<S::Command>>,
     me: usize,
-    _marker: PhantomData<S>,
+    max_raft_state: Option<usize>,
+    inner: Mutex<Inner<S>>,
+}
+
+struct Inner<S: State> {
+    state: S,
+    /// The index of the last applied command.
+    applied: u64,
+    /// The last sequence number and serialized output of each client.
+    sessions: BTreeMap<u64, (u64, Vec<u8>)>,
+    /// Waiters woken when the log is applied up to the index.
+    waiters: Vec<(u64, oneshot::Sender<()>)>,
 }
 
 impl<S: State> fmt::Debug for Server<S> {
@@ -34,14 +64,33 @@ impl<S: State> Server<S> {
         max_raft_state: Option<usize>,
     ) -> Arc<Self> {
         // You may need initialization code here.
-        let (rf, apply_ch) = raft::RaftHandle::new(servers, me).await;
+        let (rf, apply_ch) = raft::typed::RaftHandle::new(
+            servers,
+            me,
+            raft::RaftConfig {
+                batch_apply: true,
+                // so that clerks can talk to any server
+                forward_proposals: true,
+                ..raft::RaftConfig::default()
+            },
+            raft::FsStorage::new(),
+        )
+        .await
+        .expect("failed to restore");
 
         let this = Arc::new(Server {
             rf,
             me,
-            _marker: PhantomData,
+            max_raft_state,
+            inner: Mutex::new(Inner {
+                state: S::default(),
+                applied: 0,
+                sessions: BTreeMap::new(),
+                waiters: vec![],
+            }),
         });
         this.start_rpc_server();
+        this.start_applier(apply_ch);
         this
     }
 
@@ -49,12 +98,39 @@ impl<S: State> Server<S> {
         let net = net::NetLocalHandle::current();
 
         let this = self.clone();
-        net.add_rpc_handler(move |cmd: S::Command| {
+        net.add_rpc_handler(move |req: Request<S::Command>| {
             let this = this.clone();
-            async move { this.apply(cmd).await }
+            async move { this.apply(req).await }
         });
     }
 
+    /// Apply committed messages from Raft to the state machine.
+    fn start_applier(self: &Arc<Self>, mut apply_ch: raft::typed::MsgRecver<Request<S::Command>>) {
+        let this = self.clone();
+        task::spawn(async move {
+            while let Some(msg) = apply_ch.next().await {
+                match msg {
+                    raft::typed::ApplyMsg::Command { cmd, index } => {
+                        this.apply_commands(vec![(index, cmd)]);
+                        this.maybe_snapshot(index).await;
+                    }
+                    raft::typed::ApplyMsg::Commands { entries } => {
+                        let index = entries.last().map_or(0, |(index, _)| *index);
+                        this.apply_commands(entries);
+                        this.maybe_snapshot(index).await;
+                    }
+                    raft::typed::ApplyMsg::Snapshot { data, term, index } => {
+                        if this.rf.cond_install_snapshot(term, index, &data).await {
+                            this.install_snapshot(index, &data);
+                        }
+                    }
+                    raft::typed::ApplyMsg::Config { index, .. } => this.apply_config(index),
+                }
+            }
+        })
+        .detach();
+    }
+
     /// The current term of this peer.
     pub fn term(&self) -> u64 {
         self.rf.term()
@@ -65,8 +141,137 @@ impl<S: State> Server<S> {
         self.rf.is_leader()
     }
 
-    async fn apply(&self, cmd: S::Command) -> Result<S::Output, Error> {
-        todo!("apply command");
+    async fn apply(&self, req: Request<S::Command>) -> Result<S::Output, Error> {
+        if S::is_read_only(&req.cmd) {
+            // otherwise fall back to append the read to the log, which
+            // followers forward to the leader
+            if let Ok(index) = self.rf.read_index().await {
+                return self.read(index, req.cmd).await;
+            }
+        }
+        let (client, seq) = (req.client, req.seq);
+        let index = match timeout(APPLY_TIMEOUT, self.rf.propose(&req)).await {
+            Ok(Ok(raft::Committed(index))) => index,
+            Ok(Err(raft::Error::NotLeader(hint))) => return Err(Error::NotLeader { hint }),
+            Ok(Err(_)) => return Err(Error::Failed),
+            Err(_) => return Err(Error::Timeout),
+        };
+        self.wait_applied(index).await?;
+        let inner = self.inner.lock().unwrap();
+        match inner.sessions.get(&client) {
+            Some((s, output)) if *s == seq => Ok(bincode::deserialize(output).unwrap()),
+            _ => Err(Error::Failed),
+        }
+    }
+
+    /// Serve a read-only command after the log is applied up to `index`.
+    async fn read(&self, index: u64, cmd: S::Command) -> Result<S::Output, Error> {
+        self.wait_applied(index).await?;
+        let mut inner = self.inner.lock().unwrap();
+        Ok(inner.state.apply(cmd))
+    }
+
+    /// Wait until the log is applied up to `index`.
+    async fn wait_applied(&self, index: u64) -> Result<(), Error> {
+        let rx = {
+            let mut inner = self.inner.lock().unwrap();
+            if inner.applied >= index {
+                return Ok(());
+            }
+            let (tx, rx) = oneshot::channel();
+            inner.waiters.push((index, tx));
+            rx
+        };
+        match timeout(APPLY_TIMEOUT, rx).await {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(_)) => Err(Error::Failed),
+            Err(_) => Err(Error::Timeout),
+        }
+    }
+
+    /// Apply committed commands in a batch.
+    fn apply_commands(&self, entries: Vec<(u64, Request<S::Command>)>) {
+        let mut inner = self.inner.lock().unwrap();
+        let mut cmds = vec![];
+        let mut clients = vec![];
+        for (index, req) in entries {
+            if index <= inner.applied {
+                continue;
+            }
+            inner.applied = index;
+            match inner.sessions.get(&req.client) {
+                // duplicated command
+                Some((seq, _)) if *seq >= req.seq => {}
+                _ => {
+                    // the output is filled in after applying the batch
+                    inner.sessions.insert(req.client, (req.seq, vec![]));
+                    clients.push((req.client, req.seq));
+                    cmds.push(req.cmd);
+                }
+            }
+        }
+        let outputs = inner.state.apply_batch(cmds);
+        for ((client, seq), output) in clients.into_iter().zip(outputs) {
+            match inner.sessions.get_mut(&client) {
+                Some(session) if session.0 == seq => {
+                    session.1 = bincode::serialize(&output).unwrap();
+                }
+                // superseded by a later command in the batch
+                _ => {}
+            }
+        }
+        inner.wake_waiters();
+    }
+
+    /// Advance the applied index over a membership change.
+    fn apply_config(&self, index: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        if index <= inner.applied {
+            return;
+        }
+        inner.applied = index;
+        inner.wake_waiters();
+    }
+
+    fn install_snapshot(&self, index: u64, data: &[u8]) {
+        let mut inner = self.inner.lock().unwrap();
+        if index <= inner.applied {
+            return;
+        }
+        let (state, sessions) = bincode::deserialize(data).expect("invalid snapshot");
+        inner.state = state;
+        inner.sessions = sessions;
+        inner.applied = index;
+        inner.wake_waiters();
+    }
+
+    /// Take a snapshot if the Raft state grows too large.
+    async fn maybe_snapshot(&self, index: u64) {
+        match self.max_raft_state {
+            Some(max) if self.rf.state_size() >= max => {}
+            _ => return,
+        }
+        let data = {
+            let inner = self.inner.lock().unwrap();
+            bincode::serialize(&(&inner.state, &inner.sessions)).unwrap()
+        };
+        self.rf
+            .snapshot(index, &data)
+            .await
+            .expect("failed to snapshot");
+    }
+}
+
+impl<S: State> Inner<S> {
+    fn wake_waiters(&mut self) {
+        let applied = self.applied;
+        let (ready, pending) = std::mem::take(&mut self.waiters)
+            .into_iter()
+            .partition(|(index, _)| *index <= applied);
+        self.waiters = pending;
+        for (_, tx) in ready {
+            let _ = tx.send(());
+        }
     }
 }
 
@@ -74,7 +279,7 @@ pub type KvServer = Server<Kv>;
 
 #[derive(Debug, Default, Serialize, Deserialize)]
 pub struct Kv {
-    // Your data here.
+    data: BTreeMap<String, String>,
 }
 
 impl State for Kv {
@@ -82,6 +287,20 @@ impl State for Kv {
     type Output = String;
 
     fn apply(&mut self, cmd: Self::Command) -> Self::Output {
-        todo!("apply command");
+        match cmd {
+            Op::Get { key } => self.data.get(&key).cloned().unwrap_or_default(),
+            Op::Put { key, value } => {
+                self.data.insert(key, value);
+                String::new()
+            }
+            Op::Append { key, value } => {
+                self.data.entry(key).or_default().push_str(&value);
+                String::new()
+            }
+        }
+    }
+
+    fn is_read_only(cmd: &Self::Command) -> bool {
+        matches!(cmd, Op::Get { .. })
     }
 }
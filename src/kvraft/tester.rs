@@ -6,6 +6,7 @@ use std::{
 };
 
 use super::{client, server};
+use crate::raft::FsStorage;
 
 pub struct Tester {
     handle: Handle,
@@ -70,7 +71,7 @@ impl Tester {
     pub fn log_size(&self) -> usize {
         self.addrs
             .iter()
-            .map(|&addr| self.handle.fs.get_file_size(addr, "state").unwrap())
+            .map(|&addr| FsStorage::disk_size(&self.handle.fs, addr))
             .max()
             .unwrap() as usize
     }
@@ -79,7 +80,7 @@ impl Tester {
     pub fn snapshot_size(&self) -> usize {
         self.addrs
             .iter()
-            .map(|&addr| self.handle.fs.get_file_size(addr, "snapshot").unwrap())
+            .map(|&addr| FsStorage::snapshot_size(&self.handle.fs, addr))
             .max()
             .unwrap() as usize
     }
@@ -1,7 +1,10 @@
 mod raft;
+mod storage;
 #[cfg(test)]
 mod tester;
 #[cfg(test)]
 mod tests;
+pub mod typed;
 
 pub use self::raft::*;
+pub use self::storage::*;
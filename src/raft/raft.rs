@@ -1,42 +1,147 @@
-use futures::{channel::mpsc, stream::FuturesUnordered, StreamExt};
+use super::storage::{HardState, Snapshot, Storage};
+use futures::{
+    channel::{mpsc, oneshot},
+    lock::Mutex as AsyncMutex,
+    ready,
+    stream::FuturesUnordered,
+    Stream, StreamExt,
+};
 use madsim::{
-    fs, net,
+    net,
     rand::{self, Rng},
     task,
     time::*,
 };
 use serde::{Deserialize, Serialize};
 use std::{
+    collections::BTreeMap,
     fmt, io,
     net::SocketAddr,
-    sync::{Arc, Mutex},
+    ops::Range,
+    pin::Pin,
+    sync::{Arc, Mutex, Weak},
+    task::{Context, Poll},
 };
 
 #[derive(Clone)]
 pub struct RaftHandle {
     inner: Arc<Mutex<Raft>>,
+    persister: Arc<AsyncMutex<Persister>>,
+    raft_config: Arc<RaftConfig>,
 }
 
 type MsgSender = mpsc::UnboundedSender<ApplyMsg>;
-pub type MsgRecver = mpsc::UnboundedReceiver<ApplyMsg>;
+
+/// The receiving end of `apply_ch`.
+///
+/// With a bounded apply channel, receiving a message makes room for Raft to
+/// apply more entries.
+pub struct MsgRecver {
+    recver: mpsc::UnboundedReceiver<ApplyMsg>,
+    raft: Weak<Mutex<Raft>>,
+}
+
+impl Stream for MsgRecver {
+    type Item = ApplyMsg;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<ApplyMsg>> {
+        let msg = ready!(self.recver.poll_next_unpin(cx));
+        if msg.is_some() {
+            if let Some(raft) = self.raft.upgrade() {
+                let mut raft = raft.lock().unwrap();
+                raft.queued -= 1;
+                raft.apply();
+            }
+        }
+        Poll::Ready(msg)
+    }
+}
+
+/// A change of the term, the role, or the known leader of a peer.
+pub type RoleChange = (u64, Role, Option<usize>);
+pub type RoleRecver = mpsc::UnboundedReceiver<RoleChange>;
 
 /// As each Raft peer becomes aware that successive log entries are committed,
 /// the peer should send an `ApplyMsg` to the service (or tester) on the same
 /// server, via the `apply_ch` passed to `Raft::new`.
+///
+/// The no-ops appended by new leaders are not delivered, so the indices of
+/// successive messages may skip them.
 pub enum ApplyMsg {
     Command {
         data: Vec<u8>,
         index: u64,
     },
+    /// Consecutive committed commands and their indices, sent instead of
+    /// `Command` if batching is enabled.
+    Commands {
+        entries: Vec<(u64, Vec<u8>)>,
+    },
     // For 2D:
     Snapshot {
         data: Vec<u8>,
         term: u64,
         index: u64,
     },
+    /// A committed membership change.
+    ///
+    /// `peers` are the members the cluster is changing to.
+    Config {
+        peers: Vec<SocketAddr>,
+        index: u64,
+    },
+}
+
+/// Statistics of persistence.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PersistStats {
+    /// The number of requests to persist.
+    pub requests: u64,
+    /// The number of batches of changes written to the storage, each of
+    /// which ends with a sync.
+    pub syncs: u64,
 }
 
-#[derive(Debug)]
+impl PersistStats {
+    /// The number of syncs saved by group commit.
+    pub fn saved(&self) -> u64 {
+        self.requests - self.syncs
+    }
+}
+
+/// A snapshot of the state of a Raft peer for introspection.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Status {
+    /// The local id of this peer.
+    pub id: usize,
+    pub term: u64,
+    pub role: Role,
+    /// The local id of the known leader.
+    pub leader: Option<usize>,
+    /// Whether this peer is a witness.
+    pub witness: bool,
+    pub voted_for: Option<SocketAddr>,
+    pub commit_index: u64,
+    pub last_applied: u64,
+    /// The index of the first entry in the log, after the snapshot.
+    pub first_index: u64,
+    pub last_index: u64,
+    /// The index of the last entry included in the snapshot.
+    pub snapshot_index: u64,
+    /// The replication progress of each follower, if this peer is the leader.
+    pub progress: Vec<Progress>,
+}
+
+/// The replication progress of a follower.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Progress {
+    pub id: usize,
+    pub addr: SocketAddr,
+    pub match_index: u64,
+    pub next_index: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Start {
     /// The index that the command will appear at if it's ever committed.
     pub index: u64,
@@ -44,25 +149,316 @@ pub struct Start {
     pub term: u64,
 }
 
+/// A proposal committed and applied at the index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Committed(pub u64);
+
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
-    #[error("this node is not a leader, next leader: {0}")]
-    NotLeader(usize),
+    #[error("this node is not a leader, known leader: {0:?}")]
+    NotLeader(Option<usize>),
+    #[error("leader has not committed an entry in its term")]
+    LeaderNotReady,
+    #[error("a membership change is in progress")]
+    ChangePending,
+    #[error("{0} is not a learner")]
+    NotLearner(SocketAddr),
+    #[error("{0} is not a voter")]
+    NotVoter(SocketAddr),
+    #[error("the cluster must have at least one voter")]
+    NoVoters,
+    #[error("{0} failed to catch up with the leader")]
+    CatchUpFailed(SocketAddr),
+    #[error("leadership transfer is in progress")]
+    TransferInProgress,
+    #[error("leadership transfer is aborted")]
+    TransferAborted,
+    #[error("the service is falling behind applying committed entries")]
+    ApplyBacklog,
+    #[error("the leader failed to start the forwarded proposal: {0}")]
+    ForwardFailed(String),
+    #[error("leadership was lost before the proposal was committed")]
+    LeadershipLost,
+    #[error("the proposal was overwritten by an entry of term {0}")]
+    Overwritten(u64),
+    #[error("invalid config: {0}")]
+    InvalidConfig(String),
+    #[error("corrupted record in {file} at offset {offset}: {reason}")]
+    Corrupted {
+        file: String,
+        offset: u64,
+        reason: String,
+    },
     #[error("IO error")]
     IO(#[from] io::Error),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// Timing and limits of a Raft peer.
+#[derive(Debug, Clone)]
+pub struct RaftConfig {
+    /// The range of the randomized election timeout.
+    ///
+    /// A follower that heard from a leader within the lower bound rejects
+    /// PreVote.
+    pub election_timeout: Range<Duration>,
+    /// The interval between two heartbeats sent by the leader.
+    pub heartbeat_interval: Duration,
+    /// The timeout of RPCs other than votes, such as AppendEntries and
+    /// InstallSnapshot.
+    pub rpc_timeout: Duration,
+    /// The maximum number of entries in one AppendEntries.
+    pub max_entries_per_rpc: usize,
+    /// The maximum number of AppendEntries carrying entries in flight to a
+    /// follower.
+    ///
+    /// Once a follower's window is full, new entries wait until a previous
+    /// request is acknowledged, and are then sent in a batch.
+    pub max_inflight: usize,
+    /// The maximum size in bytes of a chunk of snapshot in InstallSnapshot.
+    pub snapshot_chunk_size: usize,
+    /// The number of applied entries a witness keeps before compacting them.
+    pub witness_max_log: u64,
+    /// The maximum number of rounds to catch up a new server before adding
+    /// it with [`add_server`](RaftHandle::add_server).
+    pub max_catch_up_rounds: usize,
+    /// The maximum clock drift if lease-based reads are enabled.
+    ///
+    /// With a lease, the leader serves [`read_index`] locally without a round
+    /// of heartbeats, as long as a majority acknowledged its heartbeats within
+    /// the minimum election timeout minus the drift. Followers that heard
+    /// from the leader within the minimum election timeout reject votes, so
+    /// no other leader can be elected before the lease expires, as long as
+    /// clocks drift no more than this over that period. Since a peer may
+    /// have acknowledged a lease before it restarted, it also rejects votes
+    /// for the minimum election timeout after it starts.
+    ///
+    /// [`read_index`]: RaftHandle::read_index
+    pub lease_read: Option<Duration>,
+    /// Whether to enable CheckQuorum.
+    ///
+    /// With CheckQuorum, a leader which has not heard from a quorum within an
+    /// election timeout steps down, and followers that heard from the leader
+    /// within the minimum election timeout reject votes, so that an isolated
+    /// leader does not keep claiming to be the leader.
+    pub check_quorum: bool,
+    /// Whether followers forward proposals to the leader.
+    ///
+    /// When enabled, [`start`] and [`propose`] on a follower which knows the
+    /// leader forward the command to it over RPC, instead of returning
+    /// [`Error::NotLeader`]. The index and term returned are those the leader
+    /// assigned to the command.
+    ///
+    /// [`start`]: RaftHandle::start
+    /// [`propose`]: RaftHandle::propose
+    pub forward_proposals: bool,
+    /// Whether to send committed commands in batches, as
+    /// [`ApplyMsg::Commands`].
+    pub batch_apply: bool,
+    /// The capacity of `apply_ch`, or `None` if unbounded.
+    ///
+    /// When the channel is full, Raft stops applying committed entries. Once
+    /// as many committed entries wait to be applied, the leader rejects
+    /// proposals with [`Error::ApplyBacklog`] until the service catches up.
+    pub apply_capacity: Option<usize>,
+}
+
+impl Default for RaftConfig {
+    fn default() -> Self {
+        RaftConfig {
+            election_timeout: Duration::from_millis(150)..Duration::from_millis(300),
+            heartbeat_interval: Duration::from_millis(50),
+            rpc_timeout: Duration::from_millis(100),
+            max_entries_per_rpc: 64,
+            max_inflight: 8,
+            snapshot_chunk_size: 64 * 1024,
+            witness_max_log: 64,
+            max_catch_up_rounds: 10,
+            lease_read: None,
+            check_quorum: false,
+            forward_proposals: false,
+            batch_apply: false,
+            apply_capacity: None,
+        }
+    }
+}
+
+impl RaftConfig {
+    /// Check that the values are usable together.
+    ///
+    /// A heartbeat interval of at least half the minimum election timeout
+    /// would let a follower time out on a single lost heartbeat.
+    pub fn validate(&self) -> Result<()> {
+        let invalid = |reason: &str| Err(Error::InvalidConfig(reason.into()));
+        if self.election_timeout.is_empty() {
+            return invalid("election timeout range is empty");
+        }
+        if self.heartbeat_interval.is_zero() {
+            return invalid("heartbeat interval is zero");
+        }
+        if self.heartbeat_interval >= self.election_timeout.start / 2 {
+            return invalid("heartbeat interval must be less than half the election timeout");
+        }
+        if self.rpc_timeout.is_zero() {
+            return invalid("RPC timeout is zero");
+        }
+        if self.max_entries_per_rpc == 0 {
+            return invalid("max entries per AppendEntries is zero");
+        }
+        if self.max_inflight == 0 {
+            return invalid("max inflight is zero");
+        }
+        if self.snapshot_chunk_size == 0 {
+            return invalid("snapshot chunk size is zero");
+        }
+        if self.witness_max_log == 0 {
+            return invalid("witness max log is zero");
+        }
+        if self.max_catch_up_rounds == 0 {
+            return invalid("max catch-up rounds is zero");
+        }
+        if matches!(self.lease_read, Some(drift) if drift >= self.election_timeout.start) {
+            return invalid("clock drift must be less than the election timeout");
+        }
+        if self.apply_capacity == Some(0) {
+            return invalid("apply capacity is zero");
+        }
+        Ok(())
+    }
+
+    fn random_election_timeout(&self) -> Duration {
+        rand::rng().gen_range(self.election_timeout.clone())
+    }
+}
+
 struct Raft {
+    /// Addresses of all known peers, indexed by their local id.
+    ///
+    /// It grows as new peers appear in the configuration.
     peers: Vec<SocketAddr>,
     me: usize,
+    raft_config: Arc<RaftConfig>,
     apply_ch: MsgSender,
+    /// Whether we are a witness, which has no state machine.
+    witness: bool,
+    /// The number of messages in `apply_ch` not yet received by the service.
+    queued: usize,
 
     // Your data here (2A, 2B, 2C).
     // Look at the paper's Figure 2 for a description of what
     // state a Raft server must maintain.
     state: State,
+    voted_for: Option<SocketAddr>,
+    /// The known leader of the current term.
+    leader: Option<usize>,
+    /// Subscribers of role changes, and the last change sent to them.
+    role_watchers: Vec<mpsc::UnboundedSender<RoleChange>>,
+    role_change: RoleChange,
+    log: Log,
+    snapshot: Vec<u8>,
+    /// The term and vote last persisted.
+    hard_state: HardState,
+    /// The latest configuration in the log, and the index of its entry.
+    config: Config,
+    config_index: u64,
+    /// The size of the persisted state.
+    state_size: usize,
+    /// The number of batches of changes taken to persist.
+    batches: u64,
+    persist_stats: PersistStats,
+
+    commit_index: u64,
+    last_applied: u64,
+    /// Waiters for the proposals on this leader, keyed by index, with the
+    /// term of the entries.
+    proposals: BTreeMap<u64, (u64, oneshot::Sender<Result<Committed>>)>,
+
+    // volatile state on leaders
+    next_index: Vec<u64>,
+    match_index: Vec<u64>,
+
+    /// Start an election if no message from leader is received before it.
+    election_deadline: Instant,
+    /// The last time we heard from a valid leader.
+    leader_contact: Option<Instant>,
+
+    /// The local clock used to measure leases.
+    clock: Clock,
+    /// The send time of the last heartbeat acknowledged by each peer.
+    ack_times: Vec<Option<Instant>>,
+    /// The peer that leadership is being transferred to.
+    transferee: Option<usize>,
+    /// The server being caught up before it is added to the cluster.
+    adding: Option<SocketAddr>,
+    /// The last time the leader checked that a quorum is active.
+    quorum_checked: Instant,
+
+    /// The number of AppendEntries carrying entries in flight to each peer.
+    inflight: Vec<usize>,
+    /// The number of times replication to each peer has been reset, after
+    /// which replies to earlier requests no longer count in `inflight`.
+    resets: Vec<u64>,
+
+    /// The index of the snapshot being sent to each peer, and the offset of
+    /// the next chunk to send.
+    snapshot_progress: Vec<Option<(u64, u64)>>,
+    /// The snapshot being received from the leader, with the chunks received
+    /// so far in `data`. It is dropped when the term changes.
+    incoming_snapshot: Option<InstallSnapshotArgs>,
+}
+
+/// The local clock of a peer.
+///
+/// It runs at `rate` times the speed of real time, so that tests can simulate
+/// clock drift between peers.
+#[derive(Debug, Clone, Copy)]
+struct Clock {
+    real: Instant,
+    local: Instant,
+    rate: f64,
+}
+
+impl Clock {
+    fn new() -> Self {
+        Clock {
+            real: Instant::now(),
+            local: Instant::now(),
+            rate: 1.0,
+        }
+    }
+
+    fn now(&self) -> Instant {
+        self.local + Instant::now().duration_since(self.real).mul_f64(self.rate)
+    }
+
+    fn elapsed(&self, since: Instant) -> Duration {
+        self.now().saturating_duration_since(since)
+    }
+
+    #[cfg(test)]
+    fn set_rate(&mut self, rate: f64) {
+        self.local = self.now();
+        self.real = Instant::now();
+        self.rate = rate;
+    }
+}
+
+/// Resets a pending operation of the leader when dropped.
+///
+/// The operation is reset even if the future driving it is dropped before it
+/// completes, so that it does not block the leader for the rest of its term.
+struct ResetGuard<F: FnMut(&mut Raft)> {
+    raft: Arc<Mutex<Raft>>,
+    reset: F,
+}
+
+impl<F: FnMut(&mut Raft)> Drop for ResetGuard<F> {
+    fn drop(&mut self) {
+        if let Ok(mut raft) = self.raft.lock() {
+            (self.reset)(&mut raft);
+        }
+    }
 }
 
 /// State of a raft peer.
@@ -72,11 +468,14 @@ struct State {
     role: Role,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum Role {
+/// The role of a Raft peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Role {
     Follower,
     Candidate,
     Leader,
+    /// A non-voting member which only replicates the log.
+    Learner,
 }
 
 impl Default for Role {
@@ -91,10 +490,279 @@ impl State {
     }
 }
 
-/// Data needs to be persisted.
+/// The pipeline persisting changes to storage.
+///
+/// Requests to persist wait for the storage in turn. Changes are taken in
+/// batches, and a request is done without writing if a batch taken after it
+/// has been persisted.
+struct Persister {
+    storage: Box<dyn Storage>,
+    /// The last batch persisted.
+    persisted: u64,
+}
+
+/// Changes which have not been persisted.
+#[derive(Debug, Default)]
+struct Unstable {
+    hard_state: Option<HardState>,
+    snapshot: Option<Snapshot>,
+    /// The entries starting from the index, which replace the persisted ones.
+    entries: Option<(u64, Vec<LogEntry>)>,
+}
+
+impl Unstable {
+    fn is_empty(&self) -> bool {
+        self.hard_state.is_none() && self.snapshot.is_none() && self.entries.is_none()
+    }
+}
+
+/// An entry of the Raft log.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogEntry {
+    term: u64,
+    kind: EntryKind,
+}
+
+/// What a log entry carries.
 #[derive(Debug, Clone, Serialize, Deserialize)]
-struct Persist {
-    // Your data here.
+pub enum EntryKind {
+    /// An empty entry appended by a new leader, which commits the entries of
+    /// earlier terms. It is not delivered to the service.
+    Noop,
+    /// A membership change to the configuration.
+    Config(Config),
+    /// A command of the service.
+    Command(Vec<u8>),
+}
+
+/// The members of the cluster.
+///
+/// During a membership change the cluster runs in joint consensus, where
+/// elections and commitment need separate majorities of both the old and the
+/// new members.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Config {
+    peers: Vec<SocketAddr>,
+    /// The old members in joint consensus.
+    old_peers: Option<Vec<SocketAddr>>,
+    /// Non-voting members, which are excluded from elections and commitment.
+    learners: Vec<SocketAddr>,
+}
+
+impl Config {
+    fn new(peers: Vec<SocketAddr>) -> Self {
+        Config {
+            peers,
+            old_peers: None,
+            learners: vec![],
+        }
+    }
+
+    fn is_joint(&self) -> bool {
+        self.old_peers.is_some()
+    }
+
+    fn contains(&self, addr: SocketAddr) -> bool {
+        self.peers.contains(&addr) || matches!(&self.old_peers, Some(old) if old.contains(&addr))
+    }
+
+    fn is_learner(&self, addr: SocketAddr) -> bool {
+        self.learners.contains(&addr) && !self.contains(addr)
+    }
+
+    /// Members of both the old and the new configuration.
+    fn voters(&self) -> Vec<SocketAddr> {
+        let mut voters = self.peers.clone();
+        for &addr in self.old_peers.iter().flatten() {
+            if !voters.contains(&addr) {
+                voters.push(addr);
+            }
+        }
+        voters
+    }
+
+    /// Voters and learners, to which the log is replicated.
+    fn members(&self) -> Vec<SocketAddr> {
+        let mut members = self.voters();
+        for &addr in &self.learners {
+            if !members.contains(&addr) {
+                members.push(addr);
+            }
+        }
+        members
+    }
+
+    /// The largest value that a quorum has reached, given the value of each
+    /// member.
+    ///
+    /// Return `None` if no value has been reached by a quorum.
+    fn quorum<T: Ord + Copy>(&self, value: impl Fn(SocketAddr) -> Option<T>) -> Option<T> {
+        let majority = |peers: &[SocketAddr]| {
+            let mut values = peers.iter().map(|&addr| value(addr)).collect::<Vec<_>>();
+            values.sort_unstable_by(|a, b| b.cmp(a));
+            values.get(peers.len() / 2).copied().flatten()
+        };
+        let new = majority(&self.peers);
+        match &self.old_peers {
+            Some(old) => new.min(majority(old)),
+            None => new,
+        }
+    }
+
+    /// Whether the members satisfying `pred` form a quorum.
+    fn has_quorum(&self, pred: impl Fn(SocketAddr) -> bool) -> bool {
+        self.quorum(|addr| pred(addr).then_some(())).is_some()
+    }
+}
+
+/// The log of a raft peer.
+///
+/// Entries before `offset` have been compacted into the snapshot.
+/// The first entry is a sentinel holding the term of the last included entry.
+#[derive(Debug, Clone)]
+struct Log {
+    offset: u64,
+    entries: Vec<LogEntry>,
+    /// The configuration as of `offset`.
+    config: Config,
+    /// The first index whose entry differs from the persisted one.
+    unstable: Option<u64>,
+    /// Whether the snapshot has changed since persisted.
+    snapshot_unstable: bool,
+    /// The last index whose entry has been persisted.
+    stable: u64,
+}
+
+impl Log {
+    fn new(config: Config) -> Self {
+        Log {
+            offset: 0,
+            entries: vec![LogEntry {
+                term: 0,
+                kind: EntryKind::Noop,
+            }],
+            config,
+            unstable: None,
+            snapshot_unstable: false,
+            stable: 0,
+        }
+    }
+
+    /// The index of the last entry included in the snapshot.
+    fn first_index(&self) -> u64 {
+        self.offset
+    }
+
+    fn last_index(&self) -> u64 {
+        self.offset + self.entries.len() as u64 - 1
+    }
+
+    fn last_term(&self) -> u64 {
+        self.entries.last().unwrap().term
+    }
+
+    /// The term of the entry at `index`, or `None` if it does not exist.
+    fn term(&self, index: u64) -> Option<u64> {
+        self.get(index).map(|e| e.term)
+    }
+
+    fn get(&self, index: u64) -> Option<&LogEntry> {
+        if index < self.offset {
+            return None;
+        }
+        self.entries.get((index - self.offset) as usize)
+    }
+
+    /// Entries starting from `index`.
+    fn since(&self, index: u64) -> &[LogEntry] {
+        &self.entries[(index - self.offset) as usize..]
+    }
+
+    fn push(&mut self, entry: LogEntry) {
+        self.entries.push(entry);
+        self.mark_unstable(self.last_index());
+    }
+
+    /// Remove all entries starting from `index`.
+    fn truncate(&mut self, index: u64) {
+        assert!(index > self.offset, "can not truncate compacted entries");
+        self.entries.truncate((index - self.offset) as usize);
+        self.mark_unstable(index);
+        self.stable = self.stable.min(index - 1);
+    }
+
+    fn mark_unstable(&mut self, index: u64) {
+        self.unstable = Some(self.unstable.map_or(index, |i| i.min(index)));
+    }
+
+    /// Entries up to `index` have been persisted, unless they are changed
+    /// since.
+    fn mark_stable(&mut self, index: u64) {
+        let index = self.unstable.map_or(index, |i| index.min(i - 1));
+        self.stable = self.stable.max(index);
+    }
+
+    /// Discard entries up to and including `index`, whose term is `term` and
+    /// whose configuration is `config`.
+    ///
+    /// Entries after `index` are kept only if the log has an entry at `index`
+    /// with the same term.
+    fn compact(&mut self, index: u64, term: u64, config: Config) {
+        if self.term(index) == Some(term) {
+            self.entries.drain(..(index - self.offset) as usize);
+            // the persisted entries are discarded along with the snapshot
+            self.unstable = self.unstable.map(|i| i.max(index + 1));
+        } else {
+            self.entries.truncate(1);
+            self.unstable = Some(index + 1);
+            self.stable = self.stable.min(index);
+        }
+        self.snapshot_unstable = true;
+        self.offset = index;
+        self.entries[0] = LogEntry {
+            term,
+            kind: EntryKind::Noop,
+        };
+        self.config = config;
+    }
+
+    /// The latest configuration at or before `index`, and the index of its
+    /// entry.
+    fn config_at(&self, index: u64) -> (u64, &Config) {
+        (self.offset + 1..=index.min(self.last_index()))
+            .rev()
+            .find_map(|i| match &self.get(i).unwrap().kind {
+                EntryKind::Config(config) => Some((i, config)),
+                _ => None,
+            })
+            .unwrap_or((self.offset, &self.config))
+    }
+
+    /// The last index at or before `index` which the service sees, skipping
+    /// the no-ops.
+    fn last_visible_index(&self, index: u64) -> u64 {
+        (self.offset + 1..=index)
+            .rev()
+            .find(|&i| !matches!(self.get(i).unwrap().kind, EntryKind::Noop))
+            .unwrap_or(self.offset)
+    }
+
+    /// The first index of the given term which is not compacted.
+    fn first_index_of_term(&self, index: u64) -> u64 {
+        let term = self.term(index).unwrap();
+        let mut i = index;
+        while i > self.offset + 1 && self.term(i - 1) == Some(term) {
+            i -= 1;
+        }
+        i
+    }
+
+    /// The last index of the given term, or `None` if no such entry.
+    fn last_index_of_term(&self, term: u64) -> Option<u64> {
+        (self.offset + 1..=self.last_index())
+            .rev()
+            .find(|&i| self.term(i) == Some(term))
+    }
 }
 
 impl fmt::Debug for Raft {
@@ -105,33 +773,201 @@ impl fmt::Debug for Raft {
 
 // HINT: put async functions here
 impl RaftHandle {
-    pub async fn new(peers: Vec<SocketAddr>, me: usize) -> (Self, MsgRecver) {
+    /// Create a Raft peer, which persists its state in `storage`.
+    ///
+    /// If `raft_config` is invalid, returns [`Error::InvalidConfig`].
+    /// If the state in `storage` is damaged, returns [`Error::Corrupted`].
+    pub async fn new(
+        peers: Vec<SocketAddr>,
+        me: usize,
+        raft_config: RaftConfig,
+        storage: impl Storage,
+    ) -> Result<(Self, MsgRecver)> {
+        let config = Config::new(peers.clone());
+        Self::create(peers, me, config, raft_config, Box::new(storage), false).await
+    }
+
+    /// Create a witness, which votes and counts toward commit quorums, but
+    /// never becomes the leader.
+    ///
+    /// A witness keeps only the terms and configurations of log entries, and
+    /// sends no `ApplyMsg`, so it needs no state machine.
+    pub async fn new_witness(
+        peers: Vec<SocketAddr>,
+        me: usize,
+        raft_config: RaftConfig,
+        storage: impl Storage,
+    ) -> Result<(Self, MsgRecver)> {
+        let config = Config::new(peers.clone());
+        Self::create(peers, me, config, raft_config, Box::new(storage), true).await
+    }
+
+    /// Create a Raft peer at `addr` which joins an existing cluster.
+    ///
+    /// The peer has no configuration until it hears from the leader, so it
+    /// never starts an election before being added to the cluster with
+    /// [`change_membership`](RaftHandle::change_membership).
+    pub async fn join(
+        addr: SocketAddr,
+        raft_config: RaftConfig,
+        storage: impl Storage,
+    ) -> Result<(Self, MsgRecver)> {
+        let config = Config::default();
+        Self::create(vec![addr], 0, config, raft_config, Box::new(storage), false).await
+    }
+
+    async fn create(
+        peers: Vec<SocketAddr>,
+        me: usize,
+        config: Config,
+        raft_config: RaftConfig,
+        storage: Box<dyn Storage>,
+        witness: bool,
+    ) -> Result<(Self, MsgRecver)> {
+        raft_config.validate()?;
+        let raft_config = Arc::new(raft_config);
         let (apply_ch, recver) = mpsc::unbounded();
+        let n = peers.len();
+        let clock = Clock::new();
         let inner = Arc::new(Mutex::new(Raft {
             peers,
             me,
+            raft_config: raft_config.clone(),
             apply_ch,
+            witness,
+            queued: 0,
             state: State::default(),
+            voted_for: None,
+            leader: None,
+            role_watchers: vec![],
+            role_change: (0, Role::Follower, None),
+            log: Log::new(config),
+            snapshot: vec![],
+            hard_state: HardState::default(),
+            config: Config::default(),
+            config_index: 0,
+            state_size: 0,
+            batches: 0,
+            persist_stats: PersistStats::default(),
+            commit_index: 0,
+            last_applied: 0,
+            proposals: BTreeMap::new(),
+            next_index: vec![1; n],
+            match_index: vec![0; n],
+            election_deadline: Instant::now() + raft_config.random_election_timeout(),
+            // a restarted peer may have acknowledged a leader's lease just
+            // before it crashed, so it rejects votes as if it heard from one
+            leader_contact: raft_config.lease_read.map(|_| clock.now()),
+            clock,
+            ack_times: vec![None; n],
+            transferee: None,
+            adding: None,
+            quorum_checked: Instant::now(),
+            inflight: vec![0; n],
+            resets: vec![0; n],
+            snapshot_progress: vec![None; n],
+            incoming_snapshot: None,
         }));
-        let handle = RaftHandle { inner };
+        let recver = MsgRecver {
+            recver,
+            raft: Arc::downgrade(&inner),
+        };
+        let handle = RaftHandle {
+            inner,
+            persister: Arc::new(AsyncMutex::new(Persister {
+                storage,
+                persisted: 0,
+            })),
+            raft_config,
+        };
         // initialize from state persisted before a crash
-        handle.restore().await.expect("failed to restore");
+        handle.restore().await?;
+        handle.inner.lock().unwrap().update_config();
         handle.start_rpc_server();
+        handle.start_ticker();
+        handle.start_heartbeat();
 
-        (handle, recver)
+        Ok((handle, recver))
     }
 
     /// Start agreement on the next command to be appended to Raft's log.
     ///
-    /// If this server isn't the leader, returns [`Error::NotLeader`].
+    /// If this server isn't the leader, returns [`Error::NotLeader`], unless
+    /// it forwards the command to the leader (see
+    /// [`RaftConfig::forward_proposals`]).
     /// Otherwise start the agreement and return immediately.
     ///
     /// There is no guarantee that this command will ever be committed to the
     /// Raft log, since the leader may fail or lose an election.
     pub async fn start(&self, cmd: &[u8]) -> Result<Start> {
-        let mut raft = self.inner.lock().unwrap();
-        info!("{:?} start", *raft);
-        raft.start(cmd)
+        let (start, forward) = {
+            let mut raft = self.inner.lock().unwrap();
+            info!("{:?} start", *raft);
+            (raft.start(cmd), raft.raft_config.forward_proposals)
+        };
+        match start {
+            Ok(start) => {
+                // replicate the entry while persisting it
+                self.broadcast_append_entries(false);
+                self.persist().await?;
+                Ok(start)
+            }
+            Err(Error::NotLeader(Some(leader))) if forward => self.forward_to(leader, cmd).await,
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Propose a command, and wait until it is committed and applied.
+    ///
+    /// Return the index of the command, or an error if it is overwritten or
+    /// this peer loses leadership before it is committed. In the latter case
+    /// the command may still be committed by the next leader. A follower
+    /// which forwarded the command loses it whenever its term changes, or when
+    /// it catches up by a snapshot that does not tell whether it is committed.
+    pub async fn propose(&self, cmd: &[u8]) -> Result<Committed> {
+        let rx = {
+            let mut raft = self.inner.lock().unwrap();
+            info!("{:?} propose", *raft);
+            match raft.start(cmd) {
+                Ok(start) => Ok(raft.wait_commit(start)),
+                Err(e) => Err((e, raft.raft_config.forward_proposals)),
+            }
+        };
+        let rx = match rx {
+            Ok(rx) => {
+                self.broadcast_append_entries(false);
+                self.persist().await?;
+                rx
+            }
+            Err((Error::NotLeader(Some(leader)), true)) => {
+                let start = self.forward_to(leader, cmd).await?;
+                self.inner.lock().unwrap().wait_commit(start)
+            }
+            Err((e, _)) => return Err(e),
+        };
+        rx.await.unwrap_or(Err(Error::LeadershipLost))
+    }
+
+    /// Forward a command to the leader, and return where it is in the log.
+    async fn forward_to(&self, leader: usize, cmd: &[u8]) -> Result<Start> {
+        let addr = self.inner.lock().unwrap().peers[leader];
+        let net = net::NetLocalHandle::current();
+        let args = ForwardArgs { data: cmd.into() };
+        let reply = net
+            .call_timeout::<ForwardArgs, ForwardReply>(addr, args, self.raft_config.rpc_timeout)
+            .await;
+        let raft = self.inner.lock().unwrap();
+        match reply {
+            Ok(ForwardReply::Started(start)) => Ok(start),
+            Ok(ForwardReply::NotLeader(hint)) => {
+                let hint = hint.and_then(|addr| raft.peers.iter().position(|&a| a == addr));
+                Err(Error::NotLeader(hint.filter(|&id| id != raft.me)))
+            }
+            Ok(ForwardReply::ApplyBacklog) => Err(Error::ApplyBacklog),
+            Ok(ForwardReply::Failed(reason)) => Err(Error::ForwardFailed(reason)),
+            // the command may or may not have been appended
+            Err(_) => Err(raft.not_leader()),
+        }
     }
 
     /// The current term of this peer.
@@ -146,159 +982,1902 @@ impl RaftHandle {
         raft.state.is_leader()
     }
 
-    /// A service wants to switch to snapshot.  
-    ///
-    /// Only do so if Raft hasn't have more recent info since it communicate
-    /// the snapshot on `apply_ch`.
-    pub async fn cond_install_snapshot(
-        &self,
-        last_included_term: u64,
-        last_included_index: u64,
-        snapshot: &[u8],
-    ) -> bool {
-        todo!()
+    /// The local id of the leader of the current term, if known.
+    pub fn leader(&self) -> Option<usize> {
+        let raft = self.inner.lock().unwrap();
+        raft.leader
     }
 
-    /// The service says it has created a snapshot that has all info up to and
-    /// including index. This means the service no longer needs the log through
-    /// (and including) that index. Raft should now trim its log as much as
-    /// possible.
-    pub async fn snapshot(&self, index: u64, snapshot: &[u8]) -> Result<()> {
-        todo!()
+    /// Subscribe to changes of `(term, role, leader)`, starting with the
+    /// current one.
+    pub fn watch_role(&self) -> RoleRecver {
+        let mut raft = self.inner.lock().unwrap();
+        raft.notify_role();
+        let (tx, rx) = mpsc::unbounded();
+        tx.unbounded_send(raft.role_change).unwrap();
+        raft.role_watchers.push(tx);
+        rx
     }
 
-    /// save Raft's persistent state to stable storage,
-    /// where it can later be retrieved after a crash and restart.
-    /// see paper's Figure 2 for a description of what should be persistent.
-    async fn persist(&self) -> io::Result<()> {
-        let persist: Persist = todo!("persist state");
-        let snapshot: Vec<u8> = todo!("persist snapshot");
-        let state = bincode::serialize(&persist).unwrap();
-
-        // you need to store persistent state in file "state"
-        // and store snapshot in file "snapshot".
-        // DO NOT change the file names.
-        let file = fs::File::create("state").await?;
-        file.write_all_at(&state, 0).await?;
-        // make sure data is flushed to the disk,
-        // otherwise data will be lost on power fail.
-        file.sync_all().await?;
-
-        let file = fs::File::create("snapshot").await?;
-        file.write_all_at(&snapshot, 0).await?;
-        file.sync_all().await?;
-        Ok(())
+    /// The current status of this peer.
+    pub fn status(&self) -> Status {
+        let raft = self.inner.lock().unwrap();
+        raft.status()
     }
 
-    /// Restore previously persisted state.
-    async fn restore(&self) -> io::Result<()> {
-        match fs::read("snapshot").await {
-            Ok(snapshot) => {
-                todo!("restore snapshot");
+    /// Statistics of persistence, showing how many syncs are saved by
+    /// batching concurrent requests.
+    pub fn persist_stats(&self) -> PersistStats {
+        let raft = self.inner.lock().unwrap();
+        raft.persist_stats
+    }
+
+    /// The size in bytes of the persisted Raft state, excluding the snapshot.
+    pub fn state_size(&self) -> usize {
+        let raft = self.inner.lock().unwrap();
+        raft.state_size
+    }
+
+    /// Get a commit index that is safe to serve linearizable reads at.
+    ///
+    /// The leader records its commit index, then confirms it is still the
+    /// leader with a round of heartbeats. Once the service has applied the
+    /// log up to the returned index, it can serve the read from its local
+    /// state without appending anything to the log.
+    ///
+    /// If the leader has not committed an entry in its current term, its
+    /// commit index may be stale and [`Error::LeaderNotReady`] is returned.
+    /// This lasts until the no-op appended on election commits.
+    ///
+    /// If lease-based reads are enabled and the lease is valid, the commit
+    /// index is returned immediately.
+    ///
+    /// No-ops are not delivered to the service, so the index returned is the
+    /// last one at or before the commit index that is.
+    pub async fn read_index(&self) -> Result<u64> {
+        let (index, term, config, me, followers) = {
+            let raft = self.inner.lock().unwrap();
+            if !raft.state.is_leader() {
+                return Err(raft.not_leader());
             }
-            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
-            Err(e) => return Err(e),
-        }
-        match fs::read("state").await {
-            Ok(state) => {
-                let persist: Persist = bincode::deserialize(&state).unwrap();
-                todo!("restore state");
+            if raft.log.term(raft.commit_index) != Some(raft.state.term) {
+                return Err(Error::LeaderNotReady);
+            }
+            let index = raft.log.last_visible_index(raft.commit_index);
+            if raft.lease_valid() {
+                return Ok(index);
+            }
+            (
+                index,
+                raft.state.term,
+                raft.config.clone(),
+                raft.peers[raft.me],
+                raft.followers(),
+            )
+        };
+        let mut rpcs = followers
+            .into_iter()
+            .map(|peer| async move { (peer, self.replicate(peer, true).await) })
+            .collect::<FuturesUnordered<_>>();
+        let mut acks = vec![me];
+        let mut confirmed = config.has_quorum(|addr| acks.contains(&addr));
+        while !confirmed {
+            match rpcs.next().await {
+                Some((peer, true)) => {
+                    acks.push(self.inner.lock().unwrap().peers[peer]);
+                    confirmed = config.has_quorum(|addr| acks.contains(&addr));
+                }
+                Some((_, false)) => {}
+                None => break,
             }
-            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
-            Err(e) => return Err(e),
         }
-        Ok(())
+        let raft = self.inner.lock().unwrap();
+        if !confirmed || raft.state.term != term || !raft.state.is_leader() {
+            return Err(raft.not_leader());
+        }
+        Ok(index)
     }
 
-    fn start_rpc_server(&self) {
-        let net = net::NetLocalHandle::current();
+    /// Make the local clock run at `rate` times the speed of real time.
+    #[cfg(test)]
+    pub(crate) fn set_clock_rate(&self, rate: f64) {
+        let mut raft = self.inner.lock().unwrap();
+        raft.clock.set_rate(rate);
+    }
 
-        let this = self.clone();
-        net.add_rpc_handler(move |args: RequestVoteArgs| {
-            let this = this.clone();
-            async move { this.request_vote(args).await.unwrap() }
-        });
-        // add more RPC handers here
+    /// Whether the entry at `index` is a no-op, or `None` if it is not in the
+    /// log.
+    #[cfg(test)]
+    pub(crate) fn is_noop(&self, index: u64) -> Option<bool> {
+        let raft = self.inner.lock().unwrap();
+        if index <= raft.log.first_index() {
+            // included in the snapshot
+            return None;
+        }
+        let entry = raft.log.get(index)?;
+        Some(matches!(entry.kind, EntryKind::Noop))
     }
 
-    async fn request_vote(&self, args: RequestVoteArgs) -> Result<RequestVoteReply> {
-        let reply = {
-            let mut this = self.inner.lock().unwrap();
-            this.request_vote(args)
+    /// Change the members of the cluster to `peers`.
+    ///
+    /// The change goes through joint consensus: the leader first appends a
+    /// configuration with both the old and the new members, and once it is
+    /// committed, a configuration with only the new members. Returns after
+    /// the new configuration is committed. A leader which is not one of the
+    /// new members steps down then.
+    ///
+    /// Only one change can be in progress at a time, otherwise
+    /// [`Error::ChangePending`] is returned. If `peers` is empty,
+    /// [`Error::NoVoters`] is returned.
+    pub async fn change_membership(&self, peers: Vec<SocketAddr>) -> Result<()> {
+        let entry = {
+            let mut raft = self.inner.lock().unwrap();
+            raft.change_membership(peers)?
         };
-        // if you need to persist or call async functions here,
-        // make sure the lock is scoped and dropped.
-        self.persist().await.expect("failed to persist");
+        self.commit_config(entry).await
+    }
+
+    /// Add a learner at `addr`, which replicates the log without voting.
+    ///
+    /// A learner does not count toward any quorum, so a fresh node can catch
+    /// up on the snapshot and log without affecting commit latency and
+    /// availability. Returns after the configuration is committed.
+    pub async fn add_learner(&self, addr: SocketAddr) -> Result<()> {
+        let entry = {
+            let mut raft = self.inner.lock().unwrap();
+            raft.add_learner(addr)?
+        };
+        self.commit_config(entry).await
+    }
+
+    /// Promote the learner at `addr` to a voter.
+    ///
+    /// This is a membership change through joint consensus, see
+    /// [`change_membership`](RaftHandle::change_membership).
+    pub async fn promote_learner(&self, addr: SocketAddr) -> Result<()> {
+        let entry = {
+            let mut raft = self.inner.lock().unwrap();
+            if !raft.config.is_learner(addr) {
+                return Err(Error::NotLearner(addr));
+            }
+            let mut peers = raft.config.peers.clone();
+            peers.push(addr);
+            raft.change_membership(peers)?
+        };
+        self.commit_config(entry).await
+    }
+
+    /// Add a voter at `addr` with a single-server membership change.
+    ///
+    /// The server first joins as a learner, and is caught up in rounds, each
+    /// replicating the log up to the leader's last index as of its start.
+    /// Once a round completes within an election timeout, a configuration
+    /// with the server as a voter is appended. Returns after it is committed.
+    ///
+    /// Returns [`Error::ChangePending`] if another change is in progress, or
+    /// [`Error::CatchUpFailed`] if the server does not catch up in
+    /// [`RaftConfig::max_catch_up_rounds`] rounds, leaving it a learner. Dropping the
+    /// returned future stops catching up the server as well.
+    pub async fn add_server(&self, addr: SocketAddr) -> Result<()> {
+        let learner = {
+            let mut raft = self.inner.lock().unwrap();
+            raft.check_single_change()?;
+            if raft.config.contains(addr) {
+                return Ok(());
+            }
+            let learner = if raft.config.is_learner(addr) {
+                None
+            } else {
+                Some(raft.add_learner(addr)?)
+            };
+            raft.adding = Some(addr);
+            learner
+        };
+        let guard = ResetGuard {
+            raft: self.inner.clone(),
+            reset: move |raft: &mut Raft| {
+                if raft.adding == Some(addr) {
+                    raft.adding = None;
+                }
+            },
+        };
+        let result = async {
+            if let Some(entry) = learner {
+                self.commit_config(entry).await?;
+            }
+            self.catch_up(addr).await
+        }
+        .await;
+        drop(guard);
+        let entry = {
+            let mut raft = self.inner.lock().unwrap();
+            result?;
+            raft.add_voter(addr)?
+        };
+        self.commit_config(entry).await
+    }
+
+    /// Remove the voter at `addr` with a single-server membership change.
+    ///
+    /// Returns after the configuration is committed. A removed leader steps
+    /// down then. Returns [`Error::ChangePending`] if another change is in
+    /// progress, or [`Error::NoVoters`] if `addr` is the last voter.
+    pub async fn remove_server(&self, addr: SocketAddr) -> Result<()> {
+        let entry = {
+            let mut raft = self.inner.lock().unwrap();
+            raft.remove_voter(addr)?
+        };
+        self.commit_config(entry).await
+    }
+
+    /// Replicate the log to the learner at `addr` in rounds, until a round
+    /// completes within an election timeout.
+    async fn catch_up(&self, addr: SocketAddr) -> Result<()> {
+        for round in 0..self.raft_config.max_catch_up_rounds {
+            let (peer, term, target) = {
+                let raft = self.inner.lock().unwrap();
+                if !raft.state.is_leader() {
+                    return Err(raft.not_leader());
+                }
+                let peer = raft.peers.iter().position(|&a| a == addr).unwrap();
+                (peer, raft.state.term, raft.log.last_index())
+            };
+            let t0 = Instant::now();
+            while t0.elapsed() < self.raft_config.election_timeout.start {
+                {
+                    let raft = self.inner.lock().unwrap();
+                    if raft.state.term != term || !raft.state.is_leader() {
+                        return Err(raft.not_leader());
+                    }
+                    if raft.match_index[peer] >= target {
+                        debug!("{:?} caught up {} in {} rounds", *raft, addr, round + 1);
+                        return Ok(());
+                    }
+                }
+                sleep(Duration::from_millis(10)).await;
+            }
+        }
+        Err(Error::CatchUpFailed(addr))
+    }
+
+    /// Transfer leadership to the voter at `target`.
+    ///
+    /// The leader stops accepting proposals, brings the log of the target up
+    /// to date, then sends TimeoutNow so that the target starts an election
+    /// at once. Returns after we learn about the new term. If the target is
+    /// not elected within an election timeout, the transfer is aborted with
+    /// [`Error::TransferAborted`] and we go on serving as the leader. Dropping
+    /// the returned future aborts the transfer as well.
+    pub async fn transfer_leadership(&self, target: SocketAddr) -> Result<()> {
+        let (peer, term, me) = {
+            let mut raft = self.inner.lock().unwrap();
+            if !raft.state.is_leader() {
+                return Err(raft.not_leader());
+            }
+            if target == raft.peers[raft.me] {
+                return Ok(());
+            }
+            if !raft.config.contains(target) {
+                return Err(Error::NotVoter(target));
+            }
+            if raft.transferee.is_some() {
+                return Err(Error::TransferInProgress);
+            }
+            info!("{:?} transfer leadership to {}", *raft, target);
+            let peer = raft.peers.iter().position(|&a| a == target).unwrap();
+            raft.transferee = Some(peer);
+            (peer, raft.state.term, raft.peers[raft.me])
+        };
+        let guard = ResetGuard {
+            raft: self.inner.clone(),
+            reset: move |raft: &mut Raft| {
+                if raft.state.term == term && raft.transferee == Some(peer) {
+                    raft.transferee = None;
+                }
+            },
+        };
+        let net = net::NetLocalHandle::current();
+        let result = timeout(self.raft_config.election_timeout.end, async {
+            // bring the target up to date
+            loop {
+                {
+                    let raft = self.inner.lock().unwrap();
+                    if raft.state.term != term || raft.transferee != Some(peer) {
+                        return;
+                    }
+                    if raft.match_index[peer] == raft.log.last_index() {
+                        break;
+                    }
+                }
+                if !self.replicate(peer, true).await {
+                    sleep(self.raft_config.heartbeat_interval).await;
+                }
+            }
+            // ask the target to campaign until we learn about the new term
+            let args = TimeoutNowArgs { term, leader: me };
+            while self.term() == term {
+                let _ = net
+                    .call_timeout::<TimeoutNowArgs, TimeoutNowReply>(
+                        target,
+                        args.clone(),
+                        self.raft_config.rpc_timeout,
+                    )
+                    .await;
+                sleep(self.raft_config.heartbeat_interval).await;
+            }
+        })
+        .await;
+        drop(guard);
+        let raft = self.inner.lock().unwrap();
+        if raft.state.term != term {
+            return Ok(());
+        }
+        if result.is_err() {
+            info!("{:?} abort leadership transfer to {}", *raft, target);
+        }
+        Err(Error::TransferAborted)
+    }
+
+    /// Replicate a configuration entry at (`index`, `term`), and wait until
+    /// the cluster has fully moved to a committed configuration.
+    async fn commit_config(&self, (index, term): (u64, u64)) -> Result<()> {
+        self.broadcast_append_entries(false);
+        self.persist().await?;
+        loop {
+            sleep(self.raft_config.heartbeat_interval).await;
+            let raft = self.inner.lock().unwrap();
+            if raft.commit_index >= index
+                && raft.config_index <= raft.commit_index
+                && !raft.config.is_joint()
+            {
+                return Ok(());
+            }
+            if raft.state.term != term || !raft.state.is_leader() {
+                return Err(raft.not_leader());
+            }
+        }
+    }
+
+    /// A service wants to switch to snapshot.
+    ///
+    /// Only do so if Raft hasn't have more recent info since it communicate
+    /// the snapshot on `apply_ch`.
+    ///
+    /// Raft installs a snapshot before sending it on `apply_ch`, and no
+    /// command at or before the snapshot is sent after it. So the service can
+    /// always switch to it.
+    pub async fn cond_install_snapshot(
+        &self,
+        last_included_term: u64,
+        last_included_index: u64,
+        _snapshot: &[u8],
+    ) -> bool {
+        let raft = self.inner.lock().unwrap();
+        debug!(
+            "{:?} cond install snapshot at index {} term {}",
+            *raft, last_included_index, last_included_term
+        );
+        true
+    }
+
+    /// The service says it has created a snapshot that has all info up to and
+    /// including index. This means the service no longer needs the log through
+    /// (and including) that index. Raft should now trim its log as much as
+    /// possible.
+    pub async fn snapshot(&self, index: u64, snapshot: &[u8]) -> Result<()> {
+        {
+            let mut raft = self.inner.lock().unwrap();
+            if !raft.snapshot(index, snapshot) {
+                return Ok(());
+            }
+        }
+        self.persist().await?;
+        Ok(())
+    }
+
+    /// save Raft's persistent state to stable storage,
+    /// where it can later be retrieved after a crash and restart.
+    /// see paper's Figure 2 for a description of what should be persistent.
+    async fn persist(&self) -> io::Result<()> {
+        // the changes so far are taken by the next batch at the latest
+        let mut batch = {
+            let mut raft = self.inner.lock().unwrap();
+            raft.persist_stats.requests += 1;
+            raft.batches + 1
+        };
+        // changes are taken and written in order while holding the storage
+        let mut persister = self.persister.lock().await;
+        while persister.persisted < batch {
+            let (taken, index, unstable) = {
+                let mut raft = self.inner.lock().unwrap();
+                raft.batches += 1;
+                (raft.batches, raft.log.last_index(), raft.unstable())
+            };
+            self.write_batch(&mut persister, unstable).await?;
+            persister.persisted = taken;
+
+            let mut raft = self.inner.lock().unwrap();
+            raft.state_size = persister.storage.size();
+            if raft.persisted(index) {
+                // persist the configuration appended on commit
+                batch = raft.batches + 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write a batch of changes to storage, ending with a sync.
+    async fn write_batch(&self, persister: &mut Persister, unstable: Unstable) -> io::Result<()> {
+        if !unstable.is_empty() {
+            let storage = &mut persister.storage;
+            // the vote must be persisted before any entry of its term
+            if let Some(hard_state) = unstable.hard_state {
+                storage.save_hard_state(hard_state).await?;
+            }
+            if let Some(snapshot) = unstable.snapshot {
+                storage.save_snapshot(snapshot).await?;
+            }
+            match unstable.entries {
+                Some((index, entries)) if entries.is_empty() => storage.truncate(index).await?,
+                Some((index, entries)) => storage.append(index, entries).await?,
+                None => {}
+            }
+            storage.sync().await?;
+            self.inner.lock().unwrap().persist_stats.syncs += 1;
+        }
+        Ok(())
+    }
+
+    /// Restore previously persisted state.
+    async fn restore(&self) -> Result<()> {
+        let mut persister = self.persister.lock().await;
+        let storage = &mut persister.storage;
+        let state = storage.load().await?;
+        let mut raft = self.inner.lock().unwrap();
+        raft.state.term = state.hard_state.term;
+        raft.voted_for = state.hard_state.voted_for;
+        raft.hard_state = state.hard_state;
+        if let Some(snapshot) = state.snapshot {
+            raft.log
+                .compact(snapshot.index, snapshot.term, snapshot.config);
+            raft.snapshot = snapshot.data;
+        }
+        for entry in state.entries {
+            raft.log.push(entry);
+        }
+        raft.log.unstable = None;
+        raft.log.snapshot_unstable = false;
+        raft.log.stable = raft.log.last_index();
+        raft.state_size = storage.size();
+        raft.commit_index = raft.log.first_index();
+        raft.last_applied = raft.log.first_index();
+        if raft.log.first_index() > 0 && !raft.witness {
+            // let the service restore from the snapshot
+            let msg = ApplyMsg::Snapshot {
+                data: raft.snapshot.clone(),
+                term: raft.log.term(raft.log.first_index()).unwrap(),
+                index: raft.log.first_index(),
+            };
+            raft.send_apply(msg);
+        }
+        Ok(())
+    }
+
+    fn start_rpc_server(&self) {
+        let net = net::NetLocalHandle::current();
+
+        let this = self.clone();
+        net.add_rpc_handler(move |args: RequestVoteArgs| {
+            let this = this.clone();
+            async move { this.request_vote(args).await.unwrap() }
+        });
+        let this = self.clone();
+        net.add_rpc_handler(move |args: PreVoteArgs| {
+            let this = this.clone();
+            async move { this.pre_vote(args) }
+        });
+        let this = self.clone();
+        net.add_rpc_handler(move |args: AppendEntriesArgs| {
+            let this = this.clone();
+            async move { this.append_entries(args).await.unwrap() }
+        });
+        let this = self.clone();
+        net.add_rpc_handler(move |args: InstallSnapshotArgs| {
+            let this = this.clone();
+            async move { this.install_snapshot(args).await.unwrap() }
+        });
+        let this = self.clone();
+        net.add_rpc_handler(move |args: TimeoutNowArgs| {
+            let this = this.clone();
+            async move { this.timeout_now(args).await.unwrap() }
+        });
+        let this = self.clone();
+        net.add_rpc_handler(move |args: ForwardArgs| {
+            let this = this.clone();
+            async move { this.forward(args).await }
+        });
+    }
+
+    async fn request_vote(&self, args: RequestVoteArgs) -> Result<RequestVoteReply> {
+        let reply = {
+            let mut this = self.inner.lock().unwrap();
+            this.request_vote(args)
+        };
+        // if you need to persist or call async functions here,
+        // make sure the lock is scoped and dropped.
+        self.persist().await.expect("failed to persist");
         Ok(reply)
     }
-}
 
-// HINT: put mutable non-async functions here
-impl Raft {
-    fn start(&mut self, data: &[u8]) -> Result<Start> {
-        if !self.state.is_leader() {
-            let leader = (self.me + 1) % self.peers.len();
-            return Err(Error::NotLeader(leader));
+    fn pre_vote(&self, args: PreVoteArgs) -> RequestVoteReply {
+        let this = self.inner.lock().unwrap();
+        this.pre_vote(args)
+    }
+
+    async fn append_entries(&self, args: AppendEntriesArgs) -> Result<AppendEntriesReply> {
+        let reply = {
+            let mut this = self.inner.lock().unwrap();
+            this.append_entries(args)
+        };
+        self.persist().await.expect("failed to persist");
+        Ok(reply)
+    }
+
+    async fn install_snapshot(&self, args: InstallSnapshotArgs) -> Result<InstallSnapshotReply> {
+        let reply = {
+            let mut this = self.inner.lock().unwrap();
+            this.install_snapshot(args)
+        };
+        self.persist().await.expect("failed to persist");
+        Ok(reply)
+    }
+
+    async fn timeout_now(&self, args: TimeoutNowArgs) -> Result<TimeoutNowReply> {
+        let (reply, campaign) = {
+            let mut this = self.inner.lock().unwrap();
+            this.timeout_now(args)
+        };
+        self.persist().await.expect("failed to persist");
+        if campaign {
+            task::spawn(self.clone().campaign(true)).detach();
         }
-        todo!("start agreement");
+        Ok(reply)
     }
 
-    // Here is an example to apply committed message.
-    fn apply(&self) {
-        let msg = ApplyMsg::Command {
-            data: todo!("apply msg"),
-            index: todo!("apply msg"),
+    async fn forward(&self, args: ForwardArgs) -> ForwardReply {
+        let reply = {
+            let mut this = self.inner.lock().unwrap();
+            info!("{:?} start forwarded", *this);
+            match this.start(&args.data) {
+                Ok(start) => ForwardReply::Started(start),
+                Err(Error::NotLeader(hint)) => {
+                    ForwardReply::NotLeader(hint.map(|id| this.peers[id]))
+                }
+                Err(Error::ApplyBacklog) => ForwardReply::ApplyBacklog,
+                Err(e) => ForwardReply::Failed(e.to_string()),
+            }
         };
-        self.apply_ch.unbounded_send(msg).unwrap();
+        if let ForwardReply::Started(_) = reply {
+            self.broadcast_append_entries(false);
+            // the proposer learns whether the entry is committed either way
+            if let Err(e) = self.persist().await {
+                warn!("failed to persist a forwarded proposal: {}", e);
+            }
+        }
+        reply
     }
 
-    fn request_vote(&mut self, args: RequestVoteArgs) -> RequestVoteReply {
-        todo!("handle RequestVote RPC");
+    /// Start an election when the election timer fires.
+    fn start_ticker(&self) {
+        let this = self.clone();
+        task::spawn(async move {
+            loop {
+                let deadline = this.inner.lock().unwrap().election_deadline;
+                sleep_until(deadline).await;
+                {
+                    let mut raft = this.inner.lock().unwrap();
+                    if Instant::now() < raft.election_deadline {
+                        continue;
+                    }
+                    raft.reset_election_timer();
+                    if raft.state.is_leader() {
+                        raft.step_down_if_isolated();
+                        continue;
+                    }
+                }
+                task::spawn(this.clone().campaign(false)).detach();
+            }
+        })
+        .detach();
+    }
+
+    /// Send heartbeats periodically while being the leader.
+    fn start_heartbeat(&self) {
+        let this = self.clone();
+        task::spawn(async move {
+            loop {
+                sleep(this.raft_config.heartbeat_interval).await;
+                if this.is_leader() {
+                    this.broadcast_append_entries(true);
+                }
+            }
+        })
+        .detach();
     }
 
-    // Here is an example to generate random number.
-    fn generate_election_timeout() -> Duration {
-        // see rand crate for more details
-        Duration::from_millis(rand::rng().gen_range(150..300))
+    /// Run a pre-vote round, then a real election if it may win.
+    ///
+    /// The term is only increased after learning that a majority would grant
+    /// the vote, so a node that cannot reach a quorum does not disrupt others.
+    ///
+    /// If the leader is transferring leadership to us, skip the pre-vote and
+    /// ask peers to ignore the current leader.
+    async fn campaign(self, transfer: bool) {
+        let (args, term) = {
+            let raft = self.inner.lock().unwrap();
+            if raft.state.is_leader() || raft.witness || !raft.config.contains(raft.peers[raft.me])
+            {
+                return;
+            }
+            if !transfer {
+                info!(
+                    "{:?} start pre-vote for term {}",
+                    *raft,
+                    raft.state.term + 1
+                );
+            }
+            let args = PreVoteArgs {
+                term: raft.state.term + 1,
+                candidate: raft.peers[raft.me],
+                last_log_index: raft.log.last_index(),
+                last_log_term: raft.log.last_term(),
+            };
+            (args, raft.state.term)
+        };
+        if !transfer && !self.collect_votes(args, term).await {
+            return;
+        }
+
+        let args = {
+            let mut raft = self.inner.lock().unwrap();
+            if raft.state.term != term || raft.state.is_leader() {
+                return;
+            }
+            raft.become_candidate(transfer)
+        };
+        self.persist().await.expect("failed to persist");
+        let term = args.term;
+        if !self.collect_votes(args, term).await {
+            return;
+        }
+
+        {
+            let mut raft = self.inner.lock().unwrap();
+            if raft.state.term != term || raft.state.role != Role::Candidate {
+                return;
+            }
+            raft.become_leader();
+        }
+        // replicate the no-op while persisting it
+        self.broadcast_append_entries(true);
+        self.persist().await.expect("failed to persist");
     }
 
-    // Here is an example to send RPC and manage concurrent tasks.
-    fn send_vote_request(&mut self) {
-        let args: RequestVoteArgs = todo!("construct RPC request");
-        let timeout = Self::generate_election_timeout();
+    /// Send vote requests to all peers.
+    ///
+    /// Return true as soon as a majority granted the vote in `term`.
+    async fn collect_votes<A: net::Message + Clone>(&self, args: A, term: u64) -> bool {
+        let (config, me) = {
+            let raft = self.inner.lock().unwrap();
+            (raft.config.clone(), raft.peers[raft.me])
+        };
+        let mut votes = vec![me];
+        if config.has_quorum(|addr| votes.contains(&addr)) {
+            return true;
+        }
+        let timeout = self.raft_config.random_election_timeout();
         let net = net::NetLocalHandle::current();
 
         let mut rpcs = FuturesUnordered::new();
-        for (i, &peer) in self.peers.iter().enumerate() {
-            if i == self.me {
+        for peer in config.voters() {
+            if peer == me {
                 continue;
             }
             // NOTE: `call` function takes ownerships
             let net = net.clone();
             let args = args.clone();
             rpcs.push(async move {
-                net.call_timeout::<RequestVoteArgs, RequestVoteReply>(peer, args, timeout)
-                    .await
+                let res = net
+                    .call_timeout::<A, RequestVoteReply>(peer, args, timeout)
+                    .await;
+                (peer, res)
             });
         }
 
-        // spawn a concurrent task
+        // handle RPC tasks in completion order
+        while let Some((peer, res)) = rpcs.next().await {
+            let reply = match res {
+                Ok(reply) => reply,
+                Err(_) => continue,
+            };
+            if reply.term > term {
+                let stepped_down = {
+                    let mut raft = self.inner.lock().unwrap();
+                    raft.step_down(reply.term)
+                };
+                if stepped_down {
+                    self.persist().await.expect("failed to persist");
+                }
+                return false;
+            }
+            if reply.vote_granted {
+                votes.push(peer);
+                if config.has_quorum(|addr| votes.contains(&addr)) {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    /// Send AppendEntries (or InstallSnapshot) to all followers.
+    ///
+    /// If `heartbeat` is false, only new entries are sent to followers whose
+    /// inflight window is not full.
+    fn broadcast_append_entries(&self, heartbeat: bool) {
+        let followers = self.inner.lock().unwrap().followers();
+        for peer in followers {
+            self.send_append_entries(peer, heartbeat);
+        }
+    }
+
+    /// Replicate the log to a follower in a concurrent task.
+    fn send_append_entries(&self, peer: usize, heartbeat: bool) {
+        let this = self.clone();
         task::spawn(async move {
-            // handle RPC tasks in completion order
-            while let Some(res) = rpcs.next().await {
-                todo!("handle RPC results");
+            this.replicate(peer, heartbeat).await;
+        })
+        .detach();
+    }
+
+    /// Send one AppendEntries (or InstallSnapshot) to a follower.
+    ///
+    /// If `heartbeat` is false, nothing is sent unless there are new entries
+    /// and the follower's inflight window is not full.
+    ///
+    /// Return true if the follower acknowledged us as the leader of the term.
+    async fn replicate(&self, peer: usize, heartbeat: bool) -> bool {
+        let (addr, term, sent, rpc) = {
+            let mut raft = self.inner.lock().unwrap();
+            if !raft.state.is_leader() {
+                return false;
             }
+            let rpc = match raft.replicate_rpc(peer, heartbeat) {
+                Some(rpc) => rpc,
+                None => return false,
+            };
+            (raft.peers[peer], raft.state.term, raft.clock.now(), rpc)
+        };
+        let net = net::NetLocalHandle::current();
+        let (reply_term, reaction) = match rpc {
+            Rpc::Append(args, reset) => {
+                let reply = net
+                    .call_timeout::<AppendEntriesArgs, AppendEntriesReply>(
+                        addr,
+                        args.clone(),
+                        self.raft_config.rpc_timeout,
+                    )
+                    .await;
+                let mut raft = self.inner.lock().unwrap();
+                match reply {
+                    Ok(reply) => (
+                        Some(reply.term),
+                        raft.handle_append_entries_reply(peer, args, reset, reply),
+                    ),
+                    Err(_) => (None, raft.handle_append_entries_error(peer, reset)),
+                }
+            }
+            Rpc::InstallSnapshot(args) => {
+                let reply = net
+                    .call_timeout::<InstallSnapshotArgs, InstallSnapshotReply>(
+                        addr,
+                        (*args).clone(),
+                        self.raft_config.rpc_timeout,
+                    )
+                    .await;
+                let reply = match reply {
+                    Ok(reply) => reply,
+                    Err(_) => return false,
+                };
+                let mut raft = self.inner.lock().unwrap();
+                (
+                    Some(reply.term),
+                    raft.handle_install_snapshot_reply(peer, *args, reply),
+                )
+            }
+        };
+        match reaction {
+            Reaction::Retry => self.send_append_entries(peer, true),
+            Reaction::Continue => self.send_append_entries(peer, false),
+            Reaction::StepDown => self.persist().await.expect("failed to persist"),
+            Reaction::Broadcast => {
+                self.broadcast_append_entries(false);
+                self.persist().await.expect("failed to persist");
+            }
+            Reaction::None => {}
+        }
+        if reply_term != Some(term) {
+            return false;
+        }
+        let mut raft = self.inner.lock().unwrap();
+        if raft.state.term == term && raft.state.is_leader() {
+            let ack = &mut raft.ack_times[peer];
+            *ack = (*ack).max(Some(sent));
+        }
+        true
+    }
+}
+
+/// An RPC to replicate the log to a follower.
+enum Rpc {
+    /// AppendEntries, and the number of resets of the replication if it
+    /// counts in the inflight window.
+    Append(AppendEntriesArgs, Option<u64>),
+    InstallSnapshot(Box<InstallSnapshotArgs>),
+}
+
+/// What to do after handling a reply from a follower.
+enum Reaction {
+    None,
+    /// Retry immediately with a backed up `next_index`.
+    Retry,
+    /// Send more entries if the inflight window allows.
+    Continue,
+    /// A higher term is seen and the state should be persisted.
+    StepDown,
+    /// New entries are appended and should be persisted and replicated.
+    Broadcast,
+}
+
+// HINT: put mutable non-async functions here
+impl Raft {
+    fn start(&mut self, data: &[u8]) -> Result<Start> {
+        if !self.state.is_leader() {
+            return Err(self.not_leader());
+        }
+        if let Some(transferee) = self.transferee {
+            return Err(Error::NotLeader(Some(transferee)));
+        }
+        let backlog = self.commit_index - self.last_applied;
+        if let Some(cap) = self.raft_config.apply_capacity {
+            if backlog >= cap as u64 {
+                return Err(Error::ApplyBacklog);
+            }
+        }
+        self.log.push(LogEntry {
+            term: self.state.term,
+            kind: EntryKind::Command(data.into()),
+        });
+        let index = self.log.last_index();
+        Ok(Start {
+            index,
+            term: self.state.term,
         })
-        .detach(); // NOTE: you need to detach a task explicitly, or it will be cancelled on drop
+    }
+
+    /// Append a joint configuration to change the members to `peers`.
+    ///
+    /// Return the index and term of the entry.
+    fn change_membership(&mut self, peers: Vec<SocketAddr>) -> Result<(u64, u64)> {
+        if peers.is_empty() {
+            return Err(Error::NoVoters);
+        }
+        let mut learners = self.config.learners.clone();
+        learners.retain(|addr| !peers.contains(addr));
+        self.append_config(Config {
+            old_peers: Some(self.config.peers.clone()),
+            peers,
+            learners,
+        })
+    }
+
+    /// Append a configuration which adds a learner at `addr`.
+    ///
+    /// Learners do not affect quorums, so there is no joint consensus.
+    fn add_learner(&mut self, addr: SocketAddr) -> Result<(u64, u64)> {
+        let mut config = self.config.clone();
+        if !config.contains(addr) && !config.learners.contains(&addr) {
+            config.learners.push(addr);
+        }
+        self.append_config(config)
+    }
+
+    /// Check that we can start a single-server membership change.
+    ///
+    /// The leader must have committed an entry in its term, so that no
+    /// change from an earlier term is still pending.
+    fn check_single_change(&self) -> Result<()> {
+        if !self.state.is_leader() {
+            return Err(self.not_leader());
+        }
+        if self.log.term(self.commit_index) != Some(self.state.term) {
+            return Err(Error::LeaderNotReady);
+        }
+        if self.adding.is_some() || self.config.is_joint() || self.config_index > self.commit_index
+        {
+            return Err(Error::ChangePending);
+        }
+        Ok(())
+    }
+
+    /// Append a configuration which makes the learner at `addr` a voter.
+    fn add_voter(&mut self, addr: SocketAddr) -> Result<(u64, u64)> {
+        self.check_single_change()?;
+        let mut config = self.config.clone();
+        config.learners.retain(|&a| a != addr);
+        config.peers.push(addr);
+        self.append_config(config)
+    }
+
+    /// Append a configuration without the voter at `addr`.
+    fn remove_voter(&mut self, addr: SocketAddr) -> Result<(u64, u64)> {
+        self.check_single_change()?;
+        if !self.config.peers.contains(&addr) {
+            return Err(Error::NotVoter(addr));
+        }
+        if self.config.peers.len() == 1 {
+            return Err(Error::NoVoters);
+        }
+        let mut config = self.config.clone();
+        config.peers.retain(|&a| a != addr);
+        self.append_config(config)
+    }
+
+    /// Append a configuration entry as the leader.
+    ///
+    /// Return the index and term of the entry.
+    fn append_config(&mut self, config: Config) -> Result<(u64, u64)> {
+        if !self.state.is_leader() {
+            return Err(self.not_leader());
+        }
+        if let Some(transferee) = self.transferee {
+            return Err(Error::NotLeader(Some(transferee)));
+        }
+        if self.config.is_joint() || self.config_index > self.commit_index || self.adding.is_some()
+        {
+            return Err(Error::ChangePending);
+        }
+        info!("{:?} change config to {:?}", self, config);
+        self.log.push(LogEntry {
+            term: self.state.term,
+            kind: EntryKind::Config(config),
+        });
+        self.update_config();
+        Ok((self.log.last_index(), self.state.term))
+    }
+
+    /// Adopt the latest configuration in the log.
+    fn update_config(&mut self) {
+        let (index, config) = self.log.config_at(self.log.last_index());
+        let config = config.clone();
+        for addr in config.members() {
+            self.peer_id(addr);
+        }
+        if config != self.config {
+            debug!("{:?} config {:?} at index {}", self, config, index);
+        }
+        self.config = config;
+        self.config_index = index;
+        match self.state.role {
+            Role::Follower | Role::Learner => self.state.role = self.follower_role(),
+            _ => {}
+        }
+        self.notify_role();
+    }
+
+    /// The role to take when following a leader.
+    fn follower_role(&self) -> Role {
+        if self.config.is_learner(self.peers[self.me]) {
+            Role::Learner
+        } else {
+            Role::Follower
+        }
+    }
+
+    /// The local id of a peer, registering it if it is new.
+    fn peer_id(&mut self, addr: SocketAddr) -> usize {
+        if let Some(id) = self.peers.iter().position(|&a| a == addr) {
+            return id;
+        }
+        self.peers.push(addr);
+        self.next_index.push(self.log.last_index() + 1);
+        self.match_index.push(0);
+        self.ack_times.push(None);
+        self.inflight.push(0);
+        self.resets.push(0);
+        self.snapshot_progress.push(None);
+        self.peers.len() - 1
+    }
+
+    /// Local ids of the members other than us.
+    fn followers(&self) -> Vec<usize> {
+        let me = self.peers[self.me];
+        (self.config.members().into_iter())
+            .filter(|&addr| addr != me)
+            .map(|addr| self.peers.iter().position(|&a| a == addr).unwrap())
+            .collect()
+    }
+
+    /// Entries up to `index` have been persisted.
+    ///
+    /// Return true if a new configuration is appended to the log.
+    fn persisted(&mut self, index: u64) -> bool {
+        self.log.mark_stable(index);
+        if !self.state.is_leader() {
+            return false;
+        }
+        // the leader counts itself toward a quorum only for persisted entries
+        self.match_index[self.me] = self.log.stable;
+        self.advance_commit_index()
+    }
+
+    /// Take the changes since the last persist.
+    fn unstable(&mut self) -> Unstable {
+        let mut unstable = Unstable::default();
+        let hard_state = HardState {
+            term: self.state.term,
+            voted_for: self.voted_for,
+        };
+        if hard_state != self.hard_state {
+            self.hard_state = hard_state;
+            unstable.hard_state = Some(hard_state);
+        }
+        if std::mem::take(&mut self.log.snapshot_unstable) {
+            let index = self.log.first_index();
+            unstable.snapshot = Some(Snapshot {
+                index,
+                term: self.log.term(index).unwrap(),
+                config: self.log.config.clone(),
+                data: self.snapshot.clone(),
+            });
+        }
+        if let Some(index) = self.log.unstable.take() {
+            unstable.entries = Some((index, self.log.since(index).to_vec()));
+        }
+        unstable
+    }
+
+    fn apply(&mut self) {
+        if self.witness {
+            self.last_applied = self.commit_index;
+            self.resolve_applied_proposals();
+            if self.last_applied >= self.log.first_index() + self.raft_config.witness_max_log {
+                let index = self.last_applied;
+                let term = self.log.term(index).unwrap();
+                let config = self.log.config_at(index).1.clone();
+                self.compact_witness(index, term, config);
+            }
+            return;
+        }
+        let mut batch = vec![];
+        let mut applied = vec![];
+        while self.last_applied < self.commit_index {
+            if matches!(self.raft_config.apply_capacity, Some(cap) if self.queued >= cap) {
+                // resumed once the service receives a message
+                break;
+            }
+            self.last_applied += 1;
+            let index = self.last_applied;
+            let entry = self.log.get(index).unwrap();
+            applied.push((index, entry.term));
+            match &entry.kind {
+                EntryKind::Noop => {}
+                EntryKind::Config(config) => {
+                    let msg = ApplyMsg::Config {
+                        peers: config.peers.clone(),
+                        index,
+                    };
+                    self.flush_batch(&mut batch);
+                    self.send_apply(msg);
+                }
+                EntryKind::Command(data) if self.raft_config.batch_apply => {
+                    batch.push((index, data.clone()))
+                }
+                EntryKind::Command(data) => {
+                    let data = data.clone();
+                    self.send_apply(ApplyMsg::Command { data, index });
+                }
+            }
+        }
+        self.flush_batch(&mut batch);
+        for (index, term) in applied {
+            if let Some((proposed, tx)) = self.proposals.remove(&index) {
+                let _ = tx.send(if proposed == term {
+                    Ok(Committed(index))
+                } else {
+                    Err(Error::Overwritten(term))
+                });
+            }
+        }
+    }
+
+    /// Compact the log of a witness up to `index`, with an empty snapshot.
+    fn compact_witness(&mut self, index: u64, term: u64, config: Config) {
+        self.log.compact(index, term, config);
+        self.update_config();
+        self.snapshot = vec![];
+        self.last_applied = self.last_applied.max(index);
+        self.resolve_applied_proposals();
+    }
+
+    /// Send a batch of commands in one message.
+    fn flush_batch(&mut self, batch: &mut Vec<(u64, Vec<u8>)>) {
+        if !batch.is_empty() {
+            let entries = std::mem::take(batch);
+            self.send_apply(ApplyMsg::Commands { entries });
+        }
+    }
+
+    fn send_apply(&mut self, msg: ApplyMsg) {
+        self.queued += 1;
+        self.apply_ch.unbounded_send(msg).unwrap();
+    }
+
+    /// Wait for the command started at `start` to be applied.
+    fn wait_commit(&mut self, start: Start) -> oneshot::Receiver<Result<Committed>> {
+        let (tx, rx) = oneshot::channel();
+        if start.index > self.last_applied {
+            self.proposals.insert(start.index, (start.term, tx));
+            return rx;
+        }
+        // a forwarded command may be applied before the leader replies
+        let _ = tx.send(self.applied_proposal(start));
+        rx
+    }
+
+    /// The outcome of a proposal at or before `last_applied`.
+    fn applied_proposal(&self, start: Start) -> Result<Committed> {
+        match self.log.term(start.index) {
+            Some(term) if term == start.term => Ok(Committed(start.index)),
+            Some(term) => Err(Error::Overwritten(term)),
+            // a leader never overwrites its own entries, so a snapshot ending
+            // in the term of the proposal includes it
+            None if self.log.term(self.log.first_index()) == Some(start.term) => {
+                Ok(Committed(start.index))
+            }
+            None => Err(Error::LeadershipLost),
+        }
+    }
+
+    /// Resolve the proposals applied without being sent on `apply_ch`, by a
+    /// witness or with a snapshot installed from the leader.
+    fn resolve_applied_proposals(&mut self) {
+        let rest = self.proposals.split_off(&(self.last_applied + 1));
+        for (index, (term, tx)) in std::mem::replace(&mut self.proposals, rest) {
+            let _ = tx.send(self.applied_proposal(Start { index, term }));
+        }
+    }
+
+    /// Fail the pending proposals after losing leadership.
+    fn drop_proposals(&mut self) {
+        for (_, (_, tx)) in std::mem::take(&mut self.proposals) {
+            let _ = tx.send(Err(Error::LeadershipLost));
+        }
+    }
+
+    /// Trim the log up to `index` after the service took a snapshot.
+    ///
+    /// Return false if the snapshot is outdated.
+    fn snapshot(&mut self, index: u64, snapshot: &[u8]) -> bool {
+        if index <= self.log.first_index() || index > self.last_applied {
+            return false;
+        }
+        info!("{:?} snapshot at index {}", self, index);
+        let term = self.log.term(index).unwrap();
+        let config = self.log.config_at(index).1.clone();
+        self.log.compact(index, term, config);
+        self.snapshot = snapshot.into();
+        true
+    }
+
+    fn reset_election_timer(&mut self) {
+        self.election_deadline = Instant::now() + self.raft_config.random_election_timeout();
+    }
+
+    /// Turn into a follower if `term` is newer than ours.
+    ///
+    /// Return true if the term is changed.
+    fn step_down(&mut self, term: u64) -> bool {
+        if term <= self.state.term {
+            return false;
+        }
+        info!("{:?} step down to follower in term {}", self, term);
+        self.state = State {
+            term,
+            role: self.follower_role(),
+        };
+        self.voted_for = None;
+        self.leader = None;
+        self.transferee = None;
+        self.incoming_snapshot = None;
+        self.drop_proposals();
+        self.notify_role();
+        true
+    }
+
+    fn status(&self) -> Status {
+        let progress = match self.state.role {
+            Role::Leader => (self.followers().into_iter())
+                .map(|id| Progress {
+                    id,
+                    addr: self.peers[id],
+                    match_index: self.match_index[id],
+                    next_index: self.next_index[id],
+                })
+                .collect(),
+            _ => vec![],
+        };
+        Status {
+            id: self.me,
+            term: self.state.term,
+            role: self.state.role,
+            leader: self.leader,
+            witness: self.witness,
+            voted_for: self.voted_for,
+            commit_index: self.commit_index,
+            last_applied: self.last_applied,
+            first_index: self.log.first_index() + 1,
+            last_index: self.log.last_index(),
+            snapshot_index: self.log.first_index(),
+            progress,
+        }
+    }
+
+    /// The error returned when we are not the leader.
+    fn not_leader(&self) -> Error {
+        Error::NotLeader(self.leader.filter(|&id| id != self.me))
+    }
+
+    /// Send the current `(term, role, leader)` to the watchers if changed.
+    fn notify_role(&mut self) {
+        let change = (self.state.term, self.state.role, self.leader);
+        if change == self.role_change {
+            return;
+        }
+        self.role_change = change;
+        self.role_watchers
+            .retain(|tx| tx.unbounded_send(change).is_ok());
+    }
+
+    fn become_candidate(&mut self, transfer: bool) -> RequestVoteArgs {
+        self.state = State {
+            term: self.state.term + 1,
+            role: Role::Candidate,
+        };
+        self.voted_for = Some(self.peers[self.me]);
+        self.leader = None;
+        self.incoming_snapshot = None;
+        self.reset_election_timer();
+        self.notify_role();
+        info!("{:?} start election in term {}", self, self.state.term);
+        RequestVoteArgs {
+            term: self.state.term,
+            candidate: self.peers[self.me],
+            last_log_index: self.log.last_index(),
+            last_log_term: self.log.last_term(),
+            transfer,
+        }
+    }
+
+    fn become_leader(&mut self) {
+        info!("{:?} become leader in term {}", self, self.state.term);
+        self.state.role = Role::Leader;
+        let next = self.log.last_index() + 1;
+        self.next_index.fill(next);
+        self.match_index.fill(0);
+        self.match_index[self.me] = self.log.stable;
+        self.ack_times.fill(None);
+        // replies to requests sent in earlier terms no longer count
+        self.inflight.fill(0);
+        self.resets.iter_mut().for_each(|r| *r += 1);
+        self.snapshot_progress.fill(None);
+        self.transferee = None;
+        self.adding = None;
+        self.quorum_checked = self.clock.now();
+        self.leader = Some(self.me);
+        self.notify_role();
+        // commit the entries of earlier terms without waiting for a command
+        self.log.push(LogEntry {
+            term: self.state.term,
+            kind: EntryKind::Noop,
+        });
+    }
+
+    /// Step down if no quorum has acknowledged us since the last check, when
+    /// CheckQuorum is enabled.
+    fn step_down_if_isolated(&mut self) {
+        let since = std::mem::replace(&mut self.quorum_checked, self.clock.now());
+        if !self.raft_config.check_quorum {
+            return;
+        }
+        let me = self.peers[self.me];
+        let active = self.config.has_quorum(|addr| {
+            let id = self.peers.iter().position(|&a| a == addr).unwrap();
+            addr == me || self.ack_times[id] >= Some(since)
+        });
+        if !active {
+            info!("{:?} lost contact with a quorum, step down", self);
+            self.state.role = self.follower_role();
+            self.leader = None;
+            self.drop_proposals();
+            self.notify_role();
+        }
+    }
+
+    /// Whether we heard from a live leader within the minimum election timeout.
+    fn leader_alive(&self) -> bool {
+        self.state.is_leader()
+            || matches!(self.leader_contact, Some(t) if self.clock.elapsed(t) < self.raft_config.election_timeout.start)
+    }
+
+    /// Whether the leader lease is valid.
+    ///
+    /// The lease starts at the send time of the latest heartbeat that a
+    /// majority has acknowledged.
+    fn lease_valid(&self) -> bool {
+        let max_drift = match self.raft_config.lease_read {
+            // the transferee may be elected before the lease expires
+            Some(d) if self.state.is_leader() && self.transferee.is_none() => d,
+            _ => return false,
+        };
+        let now = self.clock.now();
+        let start = self.config.quorum(|addr| {
+            let id = self.peers.iter().position(|&a| a == addr).unwrap();
+            if id == self.me {
+                Some(now)
+            } else {
+                self.ack_times[id]
+            }
+        });
+        let lease = self.raft_config.election_timeout.start - max_drift;
+        matches!(start, Some(start) if now < start + lease)
+    }
+
+    /// Whether a candidate's log is at least as up-to-date as ours.
+    fn is_up_to_date(&self, last_log_index: u64, last_log_term: u64) -> bool {
+        (last_log_term, last_log_index) >= (self.log.last_term(), self.log.last_index())
+    }
+
+    fn request_vote(&mut self, args: RequestVoteArgs) -> RequestVoteReply {
+        let sticky = self.raft_config.lease_read.is_some() || self.raft_config.check_quorum;
+        if !args.transfer && sticky && self.leader_alive() {
+            // keep the current leader
+            return RequestVoteReply {
+                term: self.state.term,
+                vote_granted: false,
+            };
+        }
+        self.step_down(args.term);
+        let vote_granted = args.term == self.state.term
+            && self.state.role != Role::Learner
+            && (self.voted_for.is_none() || self.voted_for == Some(args.candidate))
+            && self.is_up_to_date(args.last_log_index, args.last_log_term);
+        if vote_granted {
+            debug!(
+                "{:?} vote for {} in term {}",
+                self, args.candidate, args.term
+            );
+            self.voted_for = Some(args.candidate);
+            self.reset_election_timer();
+        }
+        RequestVoteReply {
+            term: self.state.term,
+            vote_granted,
+        }
+    }
+
+    /// Whether we would vote for the candidate if it started an election.
+    ///
+    /// Unlike RequestVote, this never changes our state.
+    fn pre_vote(&self, args: PreVoteArgs) -> RequestVoteReply {
+        let vote_granted = args.term > self.state.term
+            && self.state.role != Role::Learner
+            && !self.leader_alive()
+            && self.is_up_to_date(args.last_log_index, args.last_log_term);
+        RequestVoteReply {
+            term: self.state.term,
+            vote_granted,
+        }
+    }
+
+    /// Accept a message from the leader of `term`.
+    ///
+    /// Return false if the leader is stale.
+    fn accept_leader(&mut self, term: u64, leader: SocketAddr) -> bool {
+        if term < self.state.term {
+            return false;
+        }
+        self.step_down(term);
+        self.state.role = self.follower_role();
+        self.leader = Some(self.peer_id(leader));
+        self.notify_role();
+        self.leader_contact = Some(self.clock.now());
+        self.reset_election_timer();
+        true
+    }
+
+    /// Handle TimeoutNow from the leader.
+    ///
+    /// Return whether to start an election at once.
+    fn timeout_now(&mut self, args: TimeoutNowArgs) -> (TimeoutNowReply, bool) {
+        let campaign = self.accept_leader(args.term, args.leader)
+            && !self.witness
+            && self.config.contains(self.peers[self.me]);
+        if campaign {
+            info!("{:?} leadership transferred from {}", self, args.leader);
+        }
+        let reply = TimeoutNowReply {
+            term: self.state.term,
+        };
+        (reply, campaign)
+    }
+
+    fn append_entries(&mut self, args: AppendEntriesArgs) -> AppendEntriesReply {
+        let mut reply = AppendEntriesReply {
+            term: self.state.term,
+            success: false,
+            conflict_index: 0,
+            conflict_term: None,
+        };
+        if !self.accept_leader(args.term, args.leader) {
+            return reply;
+        }
+        reply.term = self.state.term;
+
+        if args.prev_log_index > self.log.last_index() {
+            reply.conflict_index = self.log.last_index() + 1;
+            return reply;
+        }
+        if args.prev_log_index < self.log.first_index() {
+            reply.conflict_index = self.log.first_index() + 1;
+            return reply;
+        }
+        let prev_term = self.log.term(args.prev_log_index).unwrap();
+        if prev_term != args.prev_log_term {
+            reply.conflict_term = Some(prev_term);
+            reply.conflict_index = self.log.first_index_of_term(args.prev_log_index);
+            return reply;
+        }
+
+        let last_new_index = args.prev_log_index + args.entries.len() as u64;
+        let mut changed = false;
+        for (i, entry) in args.entries.into_iter().enumerate() {
+            let index = args.prev_log_index + 1 + i as u64;
+            match self.log.term(index) {
+                Some(term) if term == entry.term => continue,
+                Some(_) => self.log.truncate(index),
+                None => {}
+            }
+            if self.witness && matches!(entry.kind, EntryKind::Command(_)) {
+                // keep only the metadata
+                self.log.push(LogEntry {
+                    kind: EntryKind::Command(vec![]),
+                    ..entry
+                });
+            } else {
+                self.log.push(entry);
+            }
+            changed = true;
+        }
+        if changed {
+            self.update_config();
+        }
+        // the entries after `last_new_index` may not be the leader's
+        let commit_index = args.leader_commit.min(last_new_index);
+        if commit_index > self.commit_index {
+            self.commit_index = commit_index;
+            self.apply();
+        }
+        reply.success = true;
+        reply
+    }
+
+    /// Receive a chunk of snapshot, and install the snapshot once all chunks
+    /// are received.
+    fn install_snapshot(&mut self, args: InstallSnapshotArgs) -> InstallSnapshotReply {
+        let mut reply = InstallSnapshotReply {
+            term: self.state.term,
+            offset: 0,
+            done: false,
+        };
+        if !self.accept_leader(args.term, args.leader) {
+            return reply;
+        }
+        reply.term = self.state.term;
+        if args.last_included_index <= self.commit_index {
+            // the snapshot has nothing new for us
+            self.incoming_snapshot = None;
+            reply.done = true;
+            return reply;
+        }
+        if self.witness {
+            // a witness needs no data, only where the log starts
+            self.compact_witness(
+                args.last_included_index,
+                args.last_included_term,
+                args.config,
+            );
+            self.commit_index = args.last_included_index;
+            reply.done = true;
+            return reply;
+        }
+        let incoming = match &mut self.incoming_snapshot {
+            // leaders of different terms may snapshot different data at the
+            // same index, so chunks are only spliced from the same leader
+            Some(incoming)
+                if incoming.term == args.term
+                    && incoming.leader == args.leader
+                    && incoming.last_included_index == args.last_included_index
+                    && incoming.last_included_term == args.last_included_term =>
+            {
+                incoming
+            }
+            // a new snapshot starts from the first chunk
+            _ if args.offset != 0 => return reply,
+            incoming => incoming.insert(InstallSnapshotArgs {
+                data: vec![],
+                ..args.clone()
+            }),
+        };
+        if args.offset != incoming.data.len() as u64 {
+            // resume from where we are
+            reply.offset = incoming.data.len() as u64;
+            return reply;
+        }
+        incoming.data.extend_from_slice(&args.data);
+        reply.offset = incoming.data.len() as u64;
+        if !args.done {
+            return reply;
+        }
+        let snapshot = self.incoming_snapshot.take().unwrap();
+        info!(
+            "{:?} install snapshot at index {}",
+            self, snapshot.last_included_index
+        );
+        self.log.compact(
+            snapshot.last_included_index,
+            snapshot.last_included_term,
+            snapshot.config,
+        );
+        self.update_config();
+        self.snapshot = snapshot.data.clone();
+        self.commit_index = snapshot.last_included_index;
+        self.last_applied = snapshot.last_included_index;
+        self.resolve_applied_proposals();
+        let msg = ApplyMsg::Snapshot {
+            data: snapshot.data,
+            term: snapshot.last_included_term,
+            index: snapshot.last_included_index,
+        };
+        self.send_apply(msg);
+        reply.done = true;
+        reply
+    }
+
+    /// The next RPC to replicate the log to a follower.
+    ///
+    /// New entries are sent in batches, with at most `max_inflight` batches
+    /// in flight. Otherwise a heartbeat is sent if `heartbeat` is true.
+    fn replicate_rpc(&mut self, peer: usize, heartbeat: bool) -> Option<Rpc> {
+        let next = self.next_index[peer];
+        if next <= self.log.first_index() {
+            if !heartbeat {
+                return None;
+            }
+            // resume sending the snapshot unless it has changed
+            let index = self.log.first_index();
+            let offset = match self.snapshot_progress[peer] {
+                Some((i, offset)) if i == index => (offset as usize).min(self.snapshot.len()),
+                _ => 0,
+            };
+            let end = self
+                .snapshot
+                .len()
+                .min(offset + self.raft_config.snapshot_chunk_size);
+            self.snapshot_progress[peer] = Some((index, offset as u64));
+            return Some(Rpc::InstallSnapshot(Box::new(InstallSnapshotArgs {
+                term: self.state.term,
+                leader: self.peers[self.me],
+                last_included_index: index,
+                last_included_term: self.log.term(index).unwrap(),
+                config: self.log.config.clone(),
+                offset: offset as u64,
+                data: self.snapshot[offset..end].to_vec(),
+                done: end == self.snapshot.len(),
+            })));
+        }
+        if next <= self.log.last_index() && self.inflight[peer] < self.raft_config.max_inflight {
+            let entries = self.log.since(next);
+            let entries =
+                entries[..entries.len().min(self.raft_config.max_entries_per_rpc)].to_vec();
+            self.next_index[peer] = next + entries.len() as u64;
+            self.inflight[peer] += 1;
+            let args = self.append_entries_args(next - 1, entries);
+            return Some(Rpc::Append(args, Some(self.resets[peer])));
+        }
+        if !heartbeat {
+            return None;
+        }
+        // a heartbeat follows the last acknowledged entry if others are in
+        // flight, so that it does not overtake them
+        let prev = match self.inflight[peer] {
+            0 => next - 1,
+            _ => self.match_index[peer].max(self.log.first_index()),
+        };
+        Some(Rpc::Append(self.append_entries_args(prev, vec![]), None))
+    }
+
+    fn append_entries_args(
+        &self,
+        prev_log_index: u64,
+        entries: Vec<LogEntry>,
+    ) -> AppendEntriesArgs {
+        AppendEntriesArgs {
+            term: self.state.term,
+            leader: self.peers[self.me],
+            prev_log_index,
+            prev_log_term: self.log.term(prev_log_index).unwrap(),
+            entries,
+            leader_commit: self.commit_index,
+        }
+    }
+
+    fn handle_append_entries_reply(
+        &mut self,
+        peer: usize,
+        args: AppendEntriesArgs,
+        reset: Option<u64>,
+        reply: AppendEntriesReply,
+    ) -> Reaction {
+        if self.step_down(reply.term) {
+            return Reaction::StepDown;
+        }
+        if !self.state.is_leader() || self.state.term != args.term {
+            return Reaction::None;
+        }
+        let current = reset.is_some() && reset == Some(self.resets[peer]);
+        if current {
+            self.inflight[peer] -= 1;
+        }
+        if reply.success {
+            let match_index = args.prev_log_index + args.entries.len() as u64;
+            self.next_index[peer] = self.next_index[peer].max(match_index + 1);
+            if match_index > self.match_index[peer] {
+                self.match_index[peer] = match_index;
+                if self.advance_commit_index() {
+                    return Reaction::Broadcast;
+                }
+            }
+            if self.next_index[peer] <= self.log.last_index() {
+                return Reaction::Continue;
+            }
+            return Reaction::None;
+        }
+        if reply.conflict_index == 0 {
+            // rejected because of stale term
+            return Reaction::None;
+        }
+        // ignore outdated replies
+        let outdated = match reset {
+            Some(_) => !current,
+            None => args.prev_log_index + 1 != self.next_index[peer],
+        };
+        if outdated {
+            return Reaction::None;
+        }
+        let next = match reply.conflict_term {
+            Some(term) => match self.log.last_index_of_term(term) {
+                Some(index) => index + 1,
+                None => reply.conflict_index,
+            },
+            None => reply.conflict_index,
+        };
+        self.reset_replication(peer, next);
+        Reaction::Retry
+    }
+
+    /// Handle a lost AppendEntries, whose entries may have to be sent again.
+    fn handle_append_entries_error(&mut self, peer: usize, reset: Option<u64>) -> Reaction {
+        if !self.state.is_leader() || reset.is_none() || reset != Some(self.resets[peer]) {
+            return Reaction::None;
+        }
+        let next = self.match_index[peer] + 1;
+        self.reset_replication(peer, next);
+        Reaction::Retry
+    }
+
+    /// Restart replication to a follower from `next`, discarding the requests
+    /// in flight.
+    fn reset_replication(&mut self, peer: usize, next: u64) {
+        self.next_index[peer] = next.max(self.match_index[peer] + 1).max(1);
+        self.inflight[peer] = 0;
+        self.resets[peer] += 1;
+    }
+
+    fn handle_install_snapshot_reply(
+        &mut self,
+        peer: usize,
+        args: InstallSnapshotArgs,
+        reply: InstallSnapshotReply,
+    ) -> Reaction {
+        if self.step_down(reply.term) {
+            return Reaction::StepDown;
+        }
+        if !self.state.is_leader() || self.state.term != args.term {
+            return Reaction::None;
+        }
+        if !reply.done {
+            if self.snapshot_progress[peer] != Some((args.last_included_index, args.offset)) {
+                // another chunk has been acknowledged
+                return Reaction::None;
+            }
+            // send the next chunk, or resend from where the follower is
+            self.snapshot_progress[peer] = Some((args.last_included_index, reply.offset));
+            return Reaction::Retry;
+        }
+        self.snapshot_progress[peer] = None;
+        self.match_index[peer] = self.match_index[peer].max(args.last_included_index);
+        self.next_index[peer] = self.next_index[peer].max(args.last_included_index + 1);
+        Reaction::Retry
+    }
+
+    /// Commit the highest index replicated on a quorum in the current term.
+    ///
+    /// Return true if a new configuration is appended to the log.
+    fn advance_commit_index(&mut self) -> bool {
+        let index = (self.config)
+            .quorum(|addr| {
+                let id = self.peers.iter().position(|&a| a == addr).unwrap();
+                Some(self.match_index[id])
+            })
+            .unwrap_or(0);
+        if index > self.commit_index && self.log.term(index) == Some(self.state.term) {
+            debug!("{:?} commit index {}", self, index);
+            self.commit_index = index;
+            self.apply();
+        }
+        self.advance_config()
+    }
+
+    /// Move on from a committed joint configuration to the new one, or step
+    /// down if we are no longer a member.
+    ///
+    /// Return true if a new configuration is appended to the log.
+    fn advance_config(&mut self) -> bool {
+        if !self.state.is_leader() || self.config_index > self.commit_index {
+            return false;
+        }
+        if self.config.is_joint() {
+            let config = Config {
+                old_peers: None,
+                ..self.config.clone()
+            };
+            self.log.push(LogEntry {
+                term: self.state.term,
+                kind: EntryKind::Config(config),
+            });
+            self.update_config();
+            return true;
+        }
+        if !self.config.contains(self.peers[self.me]) {
+            info!("{:?} removed from the cluster, step down", self);
+            self.state.role = self.follower_role();
+            self.leader = None;
+            self.drop_proposals();
+            self.notify_role();
+        }
+        false
     }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct RequestVoteArgs {
-    // Your data here.
+    term: u64,
+    candidate: SocketAddr,
+    last_log_index: u64,
+    last_log_term: u64,
+    /// Whether the leader is transferring leadership to the candidate.
+    ///
+    /// Voters grant the vote even if they have heard from the leader recently.
+    transfer: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct RequestVoteReply {
-    // Your data here.
+    term: u64,
+    vote_granted: bool,
+}
+
+/// Ask whether a peer would vote for us in the next term.
+///
+/// `term` is the term the candidate would campaign in, one more than its
+/// current term.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct PreVoteArgs {
+    term: u64,
+    candidate: SocketAddr,
+    last_log_index: u64,
+    last_log_term: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct AppendEntriesArgs {
+    term: u64,
+    leader: SocketAddr,
+    prev_log_index: u64,
+    prev_log_term: u64,
+    entries: Vec<LogEntry>,
+    leader_commit: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct AppendEntriesReply {
+    term: u64,
+    success: bool,
+    /// The index the leader should retry from on failure.
+    conflict_index: u64,
+    /// The term of the conflicting entry, if any.
+    conflict_term: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct InstallSnapshotArgs {
+    term: u64,
+    leader: SocketAddr,
+    last_included_index: u64,
+    last_included_term: u64,
+    config: Config,
+    /// The byte offset of the chunk in the snapshot.
+    offset: u64,
+    data: Vec<u8>,
+    /// Whether this is the last chunk.
+    done: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct InstallSnapshotReply {
+    term: u64,
+    /// The offset of the next chunk the follower expects.
+    offset: u64,
+    /// Whether the follower has the snapshot.
+    done: bool,
+}
+
+/// A command forwarded by a follower to the leader.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ForwardArgs {
+    data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum ForwardReply {
+    /// The command is appended to the leader's log.
+    Started(Start),
+    /// The peer is not the leader. The known leader is attached.
+    NotLeader(Option<SocketAddr>),
+    ApplyBacklog,
+    /// The leader failed to start the command for another reason.
+    Failed(String),
+}
+
+/// Ask a follower to start an election at once, to take over leadership.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct TimeoutNowArgs {
+    term: u64,
+    leader: SocketAddr,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct TimeoutNowReply {
+    term: u64,
 }
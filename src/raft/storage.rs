@@ -0,0 +1,644 @@
+use super::raft::{Config, Error, LogEntry, Result};
+use futures::future::BoxFuture;
+use madsim::fs;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    convert::TryInto,
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+/// Stable storage of a Raft peer.
+///
+/// The log stored is the suffix of the Raft log after the snapshot. The first
+/// stored entry has index `snapshot.index + 1`, or 1 if there's no snapshot.
+///
+/// Appended and truncated entries may be buffered until [`sync`], while other
+/// operations must not return until their results survive a crash.
+///
+/// [`sync`]: Storage::sync
+pub trait Storage: Send + 'static {
+    /// Load the state stored before a crash.
+    ///
+    /// Return [`Error::Corrupted`] if the stored state is damaged.
+    fn load(&mut self) -> BoxFuture<'_, Result<RaftState>>;
+
+    /// Save the current term and vote.
+    fn save_hard_state(&mut self, state: HardState) -> BoxFuture<'_, io::Result<()>>;
+
+    /// Replace the entries starting from `index` with `entries`.
+    fn append(&mut self, index: u64, entries: Vec<LogEntry>) -> BoxFuture<'_, io::Result<()>>;
+
+    /// Remove all entries starting from `index`.
+    fn truncate(&mut self, index: u64) -> BoxFuture<'_, io::Result<()>>;
+
+    /// Save a snapshot and discard the entries it includes.
+    fn save_snapshot(&mut self, snapshot: Snapshot) -> BoxFuture<'_, io::Result<()>>;
+
+    /// Make the changes to entries survive a crash.
+    fn sync(&mut self) -> BoxFuture<'_, io::Result<()>>;
+
+    /// The size in bytes of the stored state, excluding the snapshot data.
+    fn size(&self) -> usize;
+}
+
+/// The term and vote of a peer.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HardState {
+    pub term: u64,
+    pub voted_for: Option<SocketAddr>,
+}
+
+/// A snapshot of the service state.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+    /// The index of the last entry included.
+    pub index: u64,
+    /// The term of the last entry included.
+    pub term: u64,
+    /// The configuration as of `index`.
+    pub config: Config,
+    pub data: Vec<u8>,
+}
+
+/// The state loaded from a [`Storage`].
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct RaftState {
+    pub hard_state: HardState,
+    pub snapshot: Option<Snapshot>,
+    /// Entries after the snapshot.
+    pub entries: Vec<LogEntry>,
+}
+
+impl RaftState {
+    /// The index of the last entry included in the snapshot.
+    fn offset(&self) -> u64 {
+        self.snapshot.as_ref().map_or(0, |s| s.index)
+    }
+
+    fn append(&mut self, index: u64, entries: Vec<LogEntry>) {
+        self.truncate(index);
+        assert_eq!(
+            index,
+            self.offset() + self.entries.len() as u64 + 1,
+            "log has a hole"
+        );
+        self.entries.extend(entries);
+    }
+
+    fn truncate(&mut self, index: u64) {
+        let offset = self.offset();
+        assert!(index > offset, "can not truncate compacted entries");
+        self.entries.truncate((index - offset - 1) as usize);
+    }
+
+    fn save_snapshot(&mut self, snapshot: Snapshot) {
+        let discard = (snapshot.index - self.offset()) as usize;
+        self.entries.drain(..discard.min(self.entries.len()));
+        self.snapshot = Some(snapshot);
+    }
+}
+
+/// A storage in memory.
+///
+/// Clones share the same state, so a clone kept outside a peer can be used to
+/// restart it.
+#[derive(Debug, Default, Clone)]
+pub struct MemStorage {
+    state: Arc<Mutex<RaftState>>,
+}
+
+impl MemStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Storage for MemStorage {
+    fn load(&mut self) -> BoxFuture<'_, Result<RaftState>> {
+        let state = self.state.lock().unwrap().clone();
+        Box::pin(async move { Ok(state) })
+    }
+
+    fn save_hard_state(&mut self, hard_state: HardState) -> BoxFuture<'_, io::Result<()>> {
+        self.state.lock().unwrap().hard_state = hard_state;
+        Box::pin(async { Ok(()) })
+    }
+
+    fn append(&mut self, index: u64, entries: Vec<LogEntry>) -> BoxFuture<'_, io::Result<()>> {
+        self.state.lock().unwrap().append(index, entries);
+        Box::pin(async { Ok(()) })
+    }
+
+    fn truncate(&mut self, index: u64) -> BoxFuture<'_, io::Result<()>> {
+        self.state.lock().unwrap().truncate(index);
+        Box::pin(async { Ok(()) })
+    }
+
+    fn save_snapshot(&mut self, snapshot: Snapshot) -> BoxFuture<'_, io::Result<()>> {
+        self.state.lock().unwrap().save_snapshot(snapshot);
+        Box::pin(async { Ok(()) })
+    }
+
+    fn sync(&mut self) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn size(&self) -> usize {
+        let state = self.state.lock().unwrap();
+        let size = bincode::serialized_size(&(&state.hard_state, &state.entries)).unwrap();
+        size as usize
+    }
+}
+
+/// A storage on the file system of the current node.
+///
+/// Log entries are appended to a write-ahead log split into segment files
+/// "wal-0", "wal-1", ... The term and vote are stored in file "state", along
+/// with the first segment in use. The snapshot is stored in file "snapshot".
+///
+/// Files "state" and "snapshot" are never rewritten in place. Each has two
+/// slots, "state-0" and "state-1" for instance, and a new version is written
+/// to the slot not in use, so that a crash in the middle of it leaves the
+/// previous version intact. A slot left incomplete by a crash is ignored.
+///
+/// Taking a snapshot starts a new segment with the entries after it, and
+/// deletes the segments before. As madsim fs can not remove files, a deleted
+/// segment is truncated to empty.
+///
+/// Each file starts with a header of the format version, followed by records
+/// framed with their length and CRC-32 checksums of the length and the record.
+///
+/// Only the last segment may end with an incomplete record, left by a crash in
+/// the middle of a write. It is dropped when the log is loaded.
+#[derive(Default)]
+pub struct FsStorage {
+    /// A copy of the stored state, without the snapshot data.
+    state: RaftState,
+    /// The sequence number of the first segment in use.
+    first_segment: u64,
+    /// The size of each segment in use.
+    segments: Vec<u64>,
+    /// The last segment, if it is open for appending.
+    ///
+    /// A segment can not be reopened for writing after a restart, so a new
+    /// one is started instead.
+    active: Option<fs::File>,
+    /// Segments written since the last sync.
+    unsynced: Vec<fs::File>,
+    /// Whether the last segment is written since the last sync.
+    dirty: bool,
+    /// The size of the state file.
+    state_size: u64,
+    /// The version of the state file last saved, which is in slot
+    /// `meta_seq % 2`.
+    meta_seq: u64,
+    /// The slot of the snapshot in use.
+    snapshot_slot: u64,
+}
+
+/// The content of file "state".
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Meta {
+    /// The version, increased by each save.
+    seq: u64,
+    hard_state: HardState,
+    first_segment: u64,
+}
+
+/// A record in the write-ahead log.
+#[derive(Debug, Serialize, Deserialize)]
+enum Record {
+    /// Entries starting from `index`, replacing the existing ones.
+    Append { index: u64, entries: Vec<LogEntry> },
+    /// Remove all entries starting from `index`.
+    Truncate { index: u64 },
+}
+
+/// A new segment is started once the last one exceeds this size.
+const SEGMENT_SIZE: u64 = 64 * 1024;
+
+/// The magic number at the start of every file.
+const MAGIC: &[u8; 4] = b"RAFT";
+/// The version of the file format.
+const FORMAT_VERSION: u32 = 3;
+/// The size of the file header.
+const HEADER_SIZE: usize = 8;
+/// The size of the length and checksums before each record.
+const FRAME_SIZE: usize = 12;
+
+impl FsStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The size in bytes of the state stored on the file system of `addr`,
+    /// excluding the snapshot.
+    pub fn disk_size(fs: &fs::FsHandle, addr: SocketAddr) -> u64 {
+        let state = (0..2)
+            .map(|slot| {
+                fs.get_file_size(addr, slot_name("state", slot))
+                    .unwrap_or(0)
+            })
+            .sum::<u64>();
+        let segments = (0..)
+            .map(|seq| fs.get_file_size(addr, segment_name(seq)))
+            .take_while(|size| size.is_ok())
+            .map(|size| size.unwrap())
+            .sum::<u64>();
+        state + segments
+    }
+
+    /// The size in bytes of the snapshot stored on the file system of
+    /// `addr`.
+    pub fn snapshot_size(fs: &fs::FsHandle, addr: SocketAddr) -> u64 {
+        (0..2)
+            .map(|slot| {
+                fs.get_file_size(addr, slot_name("snapshot", slot))
+                    .unwrap_or(0)
+            })
+            .sum()
+    }
+
+    /// Delete all state on the file system of the current node.
+    ///
+    /// The node restarts as a new peer, which has forgotten its vote and log.
+    /// So it should only be used to recover a node whose state is corrupted.
+    pub async fn wipe() -> io::Result<()> {
+        for seq in 0.. {
+            match fs::metadata(segment_name(seq)).await {
+                Ok(_) => fs::File::create(segment_name(seq)).await?,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => break,
+                Err(e) => return Err(e),
+            };
+        }
+        // the state goes last, as a node without it replays the segments
+        // from the first one
+        for file in ["snapshot", "state"] {
+            for slot in 0..2 {
+                fs::File::create(slot_name(file, slot)).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn save_meta(&mut self) -> io::Result<()> {
+        let meta = Meta {
+            seq: self.meta_seq + 1,
+            hard_state: self.state.hard_state,
+            first_segment: self.first_segment,
+        };
+        let mut data = header();
+        data.extend(frame(&meta));
+        let file = fs::File::create(slot_name("state", meta.seq)).await?;
+        file.write_all_at(&data, 0).await?;
+        // make sure data is flushed to the disk,
+        // otherwise data will be lost on power fail.
+        file.sync_all().await?;
+        self.meta_seq = meta.seq;
+        self.state_size = data.len() as u64;
+        Ok(())
+    }
+
+    /// Read the record in each slot of `file`, with the slot it is in.
+    ///
+    /// Slots that do not exist or are left incomplete by a crash are skipped.
+    async fn read_slots<T: DeserializeOwned>(file: &str) -> Result<Vec<(u64, T)>> {
+        let mut records = vec![];
+        for slot in 0..2 {
+            let name = slot_name(file, slot);
+            let data = match fs::read(&name).await {
+                Ok(data) => data,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            if data.len() < HEADER_SIZE {
+                continue;
+            }
+            let mut reader = Reader::new(&name, &data)?;
+            if reader.is_end() || reader.is_torn() {
+                continue;
+            }
+            records.push((slot, reader.read()?));
+        }
+        Ok(records)
+    }
+
+    /// Append a record to the last segment, without syncing it.
+    async fn write(&mut self, record: Record) -> io::Result<()> {
+        if self.active.is_none() || *self.segments.last().unwrap() >= SEGMENT_SIZE {
+            let seq = self.first_segment + self.segments.len() as u64;
+            let file = fs::File::create(segment_name(seq)).await?;
+            file.write_all_at(&header(), 0).await?;
+            if let Some(old) = self.active.replace(file) {
+                if self.dirty {
+                    self.unsynced.push(old);
+                }
+            }
+            self.segments.push(HEADER_SIZE as u64);
+        }
+        let buf = frame(&record);
+        let file = self.active.as_ref().unwrap();
+        let size = self.segments.last_mut().unwrap();
+        file.write_all_at(&buf, *size).await?;
+        *size += buf.len() as u64;
+        self.dirty = true;
+        Ok(())
+    }
+
+    async fn sync_segments(&mut self) -> io::Result<()> {
+        for file in self.unsynced.drain(..) {
+            file.sync_all().await?;
+        }
+        if std::mem::take(&mut self.dirty) {
+            self.active.as_ref().unwrap().sync_all().await?;
+        }
+        Ok(())
+    }
+
+    /// Read records from the segments in use, and apply them to the state.
+    async fn replay(&mut self) -> Result<()> {
+        let first = self.state.offset() + 1;
+        let entries = &mut self.state.entries;
+        // an incomplete record, which must be at the end of the log
+        let mut torn = None;
+        for seq in self.first_segment.. {
+            let name = segment_name(seq);
+            let data = match fs::read(&name).await {
+                Ok(data) => data,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => break,
+                Err(e) => return Err(e.into()),
+            };
+            self.segments.push(data.len() as u64);
+            // a crash may leave a new segment without header
+            if data.is_empty() {
+                continue;
+            }
+            if let Some(e) = torn.take() {
+                return Err(e);
+            }
+            let mut reader = Reader::new(&name, &data)?;
+            while !reader.is_end() {
+                if reader.is_torn() {
+                    // a crash in the middle of the last write
+                    torn = Some(reader.corrupted("incomplete record"));
+                    break;
+                }
+                let (index, new) = match reader.read::<Record>()? {
+                    Record::Append { index, entries } => (index, entries),
+                    Record::Truncate { index } => (index, vec![]),
+                };
+                // skip entries included in the snapshot
+                let start = index.max(first);
+                if start > first + entries.len() as u64 {
+                    return Err(reader.corrupted("log has a hole"));
+                }
+                entries.truncate((start - first) as usize);
+                entries.extend(new.into_iter().skip((start - index) as usize));
+            }
+        }
+        if torn.is_some() {
+            // new records go to a new segment after a restart, which would
+            // leave the incomplete one in the middle of the log
+            self.compact().await?;
+        }
+        Ok(())
+    }
+
+    /// Start a new segment with the entries after the snapshot, and delete
+    /// the segments before.
+    async fn compact(&mut self) -> io::Result<()> {
+        let old_first = self.first_segment;
+        self.first_segment += self.segments.len() as u64;
+        self.segments.clear();
+        self.active = None;
+        let record = Record::Append {
+            index: self.state.offset() + 1,
+            entries: self.state.entries.clone(),
+        };
+        // the segments before are no longer needed
+        self.unsynced.clear();
+        self.write(record).await?;
+        self.sync_segments().await?;
+        self.save_meta().await?;
+        for seq in old_first..self.first_segment {
+            fs::File::create(segment_name(seq)).await?;
+        }
+        Ok(())
+    }
+}
+
+fn segment_name(seq: u64) -> String {
+    format!("wal-{}", seq)
+}
+
+/// The name of slot `n % 2` of `file`.
+fn slot_name(file: &str, n: u64) -> String {
+    format!("{}-{}", file, n % 2)
+}
+
+/// The header of a file.
+fn header() -> Vec<u8> {
+    let mut buf = MAGIC.to_vec();
+    buf.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    buf
+}
+
+/// Serialize a record with its length and checksums.
+fn frame(record: &impl Serialize) -> Vec<u8> {
+    let data = bincode::serialize(record).unwrap();
+    let len = (data.len() as u32).to_le_bytes();
+    let mut buf = Vec::with_capacity(FRAME_SIZE + data.len());
+    buf.extend_from_slice(&len);
+    buf.extend_from_slice(&crc32(&len).to_le_bytes());
+    buf.extend_from_slice(&crc32(&data).to_le_bytes());
+    buf.extend_from_slice(&data);
+    buf
+}
+
+/// The CRC-32 (IEEE) checksum of `data`.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+/// A reader of the records in a file.
+struct Reader<'a> {
+    file: &'a str,
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    /// Check the header of a file and read records after it.
+    fn new(file: &'a str, data: &'a [u8]) -> Result<Self> {
+        let mut reader = Reader { file, data, pos: 0 };
+        if data.len() < HEADER_SIZE || &data[..4] != MAGIC {
+            return Err(reader.corrupted("invalid header"));
+        }
+        let version = u32::from_le_bytes(data[4..8].try_into().unwrap());
+        if version != FORMAT_VERSION {
+            return Err(reader.corrupted(format!("unsupported format version {}", version)));
+        }
+        reader.pos = HEADER_SIZE;
+        Ok(reader)
+    }
+
+    fn is_end(&self) -> bool {
+        self.pos == self.data.len()
+    }
+
+    /// Whether the rest of the file is an incomplete record.
+    ///
+    /// A record with a damaged length is not taken as incomplete.
+    fn is_torn(&self) -> bool {
+        let rest = &self.data[self.pos..];
+        if rest.len() < FRAME_SIZE {
+            return true;
+        }
+        match Self::len(rest) {
+            Some(len) => rest.len() < FRAME_SIZE + len,
+            None => false,
+        }
+    }
+
+    /// The length in the frame at the start of `rest`, if its checksum
+    /// matches.
+    fn len(rest: &[u8]) -> Option<usize> {
+        let crc = u32::from_le_bytes(rest[4..8].try_into().unwrap());
+        if crc32(&rest[..4]) != crc {
+            return None;
+        }
+        Some(u32::from_le_bytes(rest[..4].try_into().unwrap()) as usize)
+    }
+
+    /// Read the next record.
+    fn read<T: DeserializeOwned>(&mut self) -> Result<T> {
+        let rest = &self.data[self.pos..];
+        if rest.len() < FRAME_SIZE {
+            return Err(self.corrupted("incomplete record"));
+        }
+        let len = Self::len(rest).ok_or_else(|| self.corrupted("length checksum mismatch"))?;
+        if rest.len() < FRAME_SIZE + len {
+            return Err(self.corrupted("incomplete record"));
+        }
+        let crc = u32::from_le_bytes(rest[8..12].try_into().unwrap());
+        let data = &rest[FRAME_SIZE..FRAME_SIZE + len];
+        if crc32(data) != crc {
+            return Err(self.corrupted("checksum mismatch"));
+        }
+        let record = bincode::deserialize(data).map_err(|e| self.corrupted(e.to_string()))?;
+        self.pos += FRAME_SIZE + len;
+        Ok(record)
+    }
+
+    /// An error of the record at the current position.
+    fn corrupted(&self, reason: impl Into<String>) -> Error {
+        Error::Corrupted {
+            file: self.file.into(),
+            offset: self.pos as u64,
+            reason: reason.into(),
+        }
+    }
+}
+
+/// Truncate the slot of file "state" written next, as if the current node
+/// crashed in the middle of saving its state.
+#[cfg(test)]
+pub(crate) async fn tear_state() -> Result<()> {
+    let metas = FsStorage::read_slots::<Meta>("state").await?;
+    let seq = metas.into_iter().map(|(_, m)| m.seq).max().unwrap_or(0);
+    fs::File::create(slot_name("state", seq + 1)).await?;
+    Ok(())
+}
+
+/// An incomplete record, as left at the end of a segment by a crash in the
+/// middle of a write.
+#[cfg(test)]
+pub(crate) fn torn_record() -> Vec<u8> {
+    let mut buf = frame(&Record::Truncate { index: 1 });
+    buf.pop();
+    buf
+}
+
+impl Storage for FsStorage {
+    fn load(&mut self) -> BoxFuture<'_, Result<RaftState>> {
+        Box::pin(async move {
+            let meta = Self::read_slots::<Meta>("state").await?;
+            let meta = match meta.into_iter().map(|(_, m)| m).max_by_key(|m| m.seq) {
+                Some(meta) => meta,
+                None => {
+                    // create the files for a new peer
+                    self.save_meta().await?;
+                    return Ok(RaftState::default());
+                }
+            };
+            self.state_size = (HEADER_SIZE + frame(&meta).len()) as u64;
+            self.meta_seq = meta.seq;
+            self.state.hard_state = meta.hard_state;
+            self.first_segment = meta.first_segment;
+            // a crash may leave the previous snapshot along with the new one
+            let snapshots = Self::read_slots::<Snapshot>("snapshot").await?;
+            if let Some((slot, snapshot)) = snapshots.into_iter().max_by_key(|(_, s)| s.index) {
+                self.snapshot_slot = slot;
+                self.state.snapshot = Some(snapshot);
+            }
+            self.replay().await?;
+            let state = self.state.clone();
+            if let Some(snapshot) = &mut self.state.snapshot {
+                snapshot.data = vec![];
+            }
+            Ok(state)
+        })
+    }
+
+    fn save_hard_state(&mut self, hard_state: HardState) -> BoxFuture<'_, io::Result<()>> {
+        self.state.hard_state = hard_state;
+        Box::pin(self.save_meta())
+    }
+
+    fn append(&mut self, index: u64, entries: Vec<LogEntry>) -> BoxFuture<'_, io::Result<()>> {
+        self.state.append(index, entries.clone());
+        Box::pin(self.write(Record::Append { index, entries }))
+    }
+
+    fn truncate(&mut self, index: u64) -> BoxFuture<'_, io::Result<()>> {
+        self.state.truncate(index);
+        Box::pin(self.write(Record::Truncate { index }))
+    }
+
+    fn save_snapshot(&mut self, mut snapshot: Snapshot) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(async move {
+            // the snapshot is written before the segments are deleted, and
+            // the one in use is kept until then
+            let slot = self.snapshot_slot + 1;
+            let mut data = header();
+            data.extend(frame(&snapshot));
+            let file = fs::File::create(slot_name("snapshot", slot)).await?;
+            file.write_all_at(&data, 0).await?;
+            file.sync_all().await?;
+            snapshot.data = vec![];
+            self.state.save_snapshot(snapshot);
+            let old = std::mem::replace(&mut self.snapshot_slot, slot % 2);
+            self.compact().await?;
+            fs::File::create(slot_name("snapshot", old)).await?;
+            Ok(())
+        })
+    }
+
+    fn sync(&mut self) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(self.sync_segments())
+    }
+
+    fn size(&self) -> usize {
+        (self.state_size + self.segments.iter().sum::<u64>()) as usize
+    }
+}
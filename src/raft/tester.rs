@@ -1,17 +1,19 @@
-use super::raft::*;
-use futures::StreamExt;
+use super::{raft::*, storage::*};
+use futures::{future::BoxFuture, Future, StreamExt};
 use log::*;
 use madsim::{
+    fs,
     rand::{self, Rng},
     time::{self, Instant},
-    Handle,
+    Handle, LocalHandle,
 };
 use serde::{Deserialize, Serialize};
 use std::{
     collections::HashMap,
+    io,
     net::SocketAddr,
     sync::{
-        atomic::{AtomicBool, Ordering},
+        atomic::{AtomicBool, AtomicU64, Ordering},
         Arc, Mutex,
     },
     time::Duration,
@@ -23,32 +25,116 @@ pub struct RaftTester {
     addrs: Vec<SocketAddr>,
     rafts: Mutex<Vec<Option<RaftHandle>>>,
     connected: Vec<AtomicBool>,
+    /// Servers in the current configuration.
+    members: Mutex<Vec<usize>>,
     storage: StorageHandle,
+    /// Whether servers take snapshots.
+    snapshot: bool,
+    /// The server which runs as a witness, if any.
+    witness: Option<usize>,
+    /// The timing and limits of all servers.
+    raft_config: RaftConfig,
+    /// The in-memory storage of each server which survives restarts,
+    /// or `None` if servers persist to the file system.
+    mem_storage: Option<Vec<MemStorage>>,
+    /// The time each server takes to sync its storage.
+    disk_delays: Vec<Arc<Mutex<Duration>>>,
+    /// The number of bytes each server has written to its storage.
+    disk_writes: Vec<Arc<AtomicU64>>,
+    /// The time each server takes to apply a message.
+    apply_delays: Vec<Arc<Mutex<Duration>>>,
     // stat
     t0: Instant,
 }
 
 pub const SNAPSHOT_INTERVAL: u64 = 10;
 
+/// The Raft config the tests run with, selected by the `RAFT_CONFIG`
+/// environment variable:
+///
+/// - `default`: [`RaftConfig::default`].
+/// - `slow`: long election timeouts, heartbeats and RPC timeouts.
+/// - `small`: small AppendEntries, inflight windows, snapshot chunks, witness
+///   logs and catch-up rounds.
+pub fn raft_config() -> RaftConfig {
+    let config = match std::env::var("RAFT_CONFIG").as_deref() {
+        Err(_) | Ok("default") => RaftConfig::default(),
+        Ok("slow") => RaftConfig {
+            election_timeout: Duration::from_millis(300)..Duration::from_millis(500),
+            heartbeat_interval: Duration::from_millis(90),
+            rpc_timeout: Duration::from_millis(200),
+            ..RaftConfig::default()
+        },
+        Ok("small") => RaftConfig {
+            max_entries_per_rpc: 16,
+            max_inflight: 2,
+            snapshot_chunk_size: 64,
+            witness_max_log: 8,
+            max_catch_up_rounds: 3,
+            ..RaftConfig::default()
+        },
+        Ok(name) => panic!("unknown RAFT_CONFIG: {}", name),
+    };
+    config.validate().expect("invalid RAFT_CONFIG");
+    config
+}
+
 impl RaftTester {
     pub async fn new(n: usize) -> Self {
-        Self::new_ext(n, false).await
+        Self::new_ext(n, 0, false, false, None, raft_config()).await
     }
 
     pub async fn new_with_snapshot(n: usize) -> Self {
-        Self::new_ext(n, true).await
+        Self::new_ext(n, 0, true, false, None, raft_config()).await
+    }
+
+    /// Create a cluster whose servers persist to [`MemStorage`].
+    pub async fn new_with_mem_storage(n: usize, snapshot: bool) -> Self {
+        Self::new_ext(n, 0, snapshot, true, None, raft_config()).await
+    }
+
+    /// Create a cluster of `n` servers, with `spares` more servers that can be
+    /// added to it later by [`add_server`](RaftTester::add_server).
+    pub async fn new_with_spares(n: usize, spares: usize, snapshot: bool) -> Self {
+        Self::new_ext(n, spares, snapshot, false, None, raft_config()).await
     }
 
-    async fn new_ext(n: usize, snapshot: bool) -> Self {
+    /// Create a cluster of `n` servers, the last of which is a witness.
+    pub async fn new_with_witness(n: usize, snapshot: bool) -> Self {
+        Self::new_ext(n, 0, snapshot, false, Some(n - 1), raft_config()).await
+    }
+
+    /// Create a cluster whose servers run with `config`, also after restarts.
+    pub async fn new_with_config(n: usize, snapshot: bool, config: RaftConfig) -> Self {
+        Self::new_ext(n, 0, snapshot, false, None, config).await
+    }
+
+    async fn new_ext(
+        n: usize,
+        spares: usize,
+        snapshot: bool,
+        mem_storage: bool,
+        witness: Option<usize>,
+        raft_config: RaftConfig,
+    ) -> Self {
         let handle = Handle::current();
+        let total = n + spares;
         let tester = RaftTester {
             n,
-            addrs: (0..n)
+            addrs: (0..total)
                 .map(|i| SocketAddr::from(([0, 0, 1, i as _], 0)))
                 .collect::<Vec<_>>(),
-            rafts: Mutex::new(vec![None; n]),
-            connected: (0..n).map(|_| AtomicBool::new(false)).collect(),
-            storage: StorageHandle::new(n),
+            rafts: Mutex::new(vec![None; total]),
+            connected: (0..total).map(|_| AtomicBool::new(false)).collect(),
+            members: Mutex::new((0..n).collect()),
+            storage: StorageHandle::new(total),
+            snapshot,
+            witness,
+            raft_config,
+            mem_storage: mem_storage.then(|| (0..total).map(|_| MemStorage::new()).collect()),
+            disk_delays: (0..total).map(|_| Default::default()).collect(),
+            disk_writes: (0..total).map(|_| Default::default()).collect(),
+            apply_delays: (0..total).map(|_| Default::default()).collect(),
             t0: Instant::now(),
             handle,
         };
@@ -140,21 +226,80 @@ impl RaftTester {
         self.rafts.lock().unwrap()[i].is_some()
     }
 
+    pub fn is_leader(&self, i: usize) -> bool {
+        self.rafts.lock().unwrap()[i].as_ref().unwrap().is_leader()
+    }
+
     pub fn term(&self, i: usize) -> u64 {
         self.rafts.lock().unwrap()[i].as_ref().unwrap().term()
     }
 
+    /// The leader known by server i.
+    pub fn leader(&self, i: usize) -> Option<usize> {
+        self.rafts.lock().unwrap()[i].as_ref().unwrap().leader()
+    }
+
+    pub fn status(&self, i: usize) -> Status {
+        self.rafts.lock().unwrap()[i].as_ref().unwrap().status()
+    }
+
+    /// Check invariants on the status of all running servers.
+    pub fn check_status(&self) {
+        let mut leaders = HashMap::new();
+        for (i, raft) in self.rafts.lock().unwrap().iter().enumerate() {
+            let status = match raft {
+                Some(raft) => raft.status(),
+                None => continue,
+            };
+            assert!(
+                status.snapshot_index <= status.last_applied
+                    && status.last_applied <= status.commit_index
+                    && status.commit_index <= status.last_index,
+                "server {} has inconsistent indices: {:?}",
+                i,
+                status
+            );
+            if status.role != Role::Leader {
+                assert!(status.progress.is_empty(), "follower {} has progress", i);
+                continue;
+            }
+            if let Some(other) = leaders.insert(status.term, i) {
+                panic!("term {} has leaders {} and {}", status.term, other, i);
+            }
+            for p in &status.progress {
+                assert!(
+                    p.match_index < p.next_index && p.next_index <= status.last_index + 1,
+                    "leader {} has invalid progress: {:?}",
+                    i,
+                    p
+                );
+            }
+        }
+    }
+
+    /// Subscribe to role changes of server i.
+    pub fn watch_role(&self, i: usize) -> RoleRecver {
+        self.rafts.lock().unwrap()[i].as_ref().unwrap().watch_role()
+    }
+
     pub fn rpc_total(&self) -> u64 {
         self.handle.net.stat().msg_count / 2
     }
 
     /// Maximum log size across all servers
     pub fn log_size(&self) -> usize {
-        self.addrs
-            .iter()
-            .map(|&addr| self.handle.fs.get_file_size(addr, "state").unwrap())
+        (0..self.addrs.len())
+            .map(|i| self.disk_size(i))
             .max()
-            .unwrap() as usize
+            .unwrap()
+    }
+
+    /// Size of the persisted state of server i.
+    pub fn disk_size(&self, i: usize) -> usize {
+        match &self.mem_storage {
+            Some(storage) => storage[i].size(),
+            None => FsStorage::disk_size(&self.handle.fs, self.addrs[i]) as usize,
+        }
     }
 
     /// How many servers think a log entry is committed?
@@ -170,6 +315,189 @@ impl RaftTester {
             .await
     }
 
+    /// Propose a command on server i, and wait until it is committed.
+    pub async fn propose(&self, i: usize, cmd: Entry) -> Result<Committed> {
+        let raft = self.rafts.lock().unwrap()[i].as_ref().unwrap().clone();
+        self.handle
+            .local_handle(self.addrs[i])
+            .spawn(async move { raft.propose(&bincode::serialize(&cmd).unwrap()).await })
+            .await
+    }
+
+    pub async fn read_index(&self, i: usize) -> Result<u64> {
+        let raft = self.rafts.lock().unwrap()[i].as_ref().unwrap().clone();
+        self.handle
+            .local_handle(self.addrs[i])
+            .spawn(async move { raft.read_index().await })
+            .await
+    }
+
+    /// Ask server i to transfer its leadership to server `target`.
+    pub async fn transfer_leadership(&self, i: usize, target: usize) -> Result<()> {
+        let raft = self.rafts.lock().unwrap()[i].as_ref().unwrap().clone();
+        let target = self.addrs[target];
+        self.handle
+            .local_handle(self.addrs[i])
+            .spawn(async move { raft.transfer_leadership(target).await })
+            .await
+    }
+
+    /// Make the clock of server i run at `rate` times the speed of real time.
+    pub fn set_clock_rate(&self, i: usize, rate: f64) {
+        self.rafts.lock().unwrap()[i]
+            .as_ref()
+            .unwrap()
+            .set_clock_rate(rate);
+    }
+
+    /// The highest index committed by any server.
+    pub fn max_committed(&self) -> u64 {
+        self.storage.max_index() as u64
+    }
+
+    /// Servers in the current configuration.
+    pub fn members(&self) -> Vec<usize> {
+        self.members.lock().unwrap().clone()
+    }
+
+    /// Start server i and add it to the cluster.
+    pub async fn add_server(&self, i: usize) {
+        debug!("add_server({})", i);
+        if !self.is_started(i) {
+            self.start1_ext(i, self.snapshot).await;
+            self.connect(i);
+        }
+        let mut members = self.members();
+        assert!(!members.contains(&i), "server {} is already a member", i);
+        members.push(i);
+        self.change_membership(members).await;
+    }
+
+    /// Remove server i from the cluster. It keeps running.
+    pub async fn remove_server(&self, i: usize) {
+        debug!("remove_server({})", i);
+        let mut members = self.members();
+        members.retain(|&j| j != i);
+        self.change_membership(members).await;
+    }
+
+    /// Start server i and add it to the cluster with a single-server change.
+    pub async fn add_server1(&self, i: usize) {
+        debug!("add_server1({})", i);
+        if !self.is_started(i) {
+            self.start1_ext(i, self.snapshot).await;
+            self.connect(i);
+        }
+        let addr = self.addrs[i];
+        self.reconfigure(|raft| async move { raft.add_server(addr).await })
+            .await;
+        self.members.lock().unwrap().push(i);
+    }
+
+    /// Remove server i from the cluster with a single-server change.
+    pub async fn remove_server1(&self, i: usize) {
+        debug!("remove_server1({})", i);
+        let addr = self.addrs[i];
+        self.reconfigure(|raft| async move { raft.remove_server(addr).await })
+            .await;
+        self.members.lock().unwrap().retain(|&j| j != i);
+    }
+
+    /// Ask server i to add server j with a single-server change, without
+    /// starting it.
+    pub async fn try_add_server1(&self, i: usize, j: usize) -> Result<()> {
+        let raft = self.rafts.lock().unwrap()[i].as_ref().unwrap().clone();
+        let addr = self.addrs[j];
+        self.handle
+            .local_handle(self.addrs[i])
+            .spawn(async move { raft.add_server(addr).await })
+            .await
+    }
+
+    /// Ask server i to remove server j with a single-server change.
+    pub async fn try_remove_server1(&self, i: usize, j: usize) -> Result<()> {
+        let raft = self.rafts.lock().unwrap()[i].as_ref().unwrap().clone();
+        let addr = self.addrs[j];
+        self.handle
+            .local_handle(self.addrs[i])
+            .spawn(async move { raft.remove_server(addr).await })
+            .await
+    }
+
+    /// Ask server i to change the configuration to `members`.
+    pub async fn try_change_membership(&self, i: usize, members: Vec<usize>) -> Result<()> {
+        let raft = self.rafts.lock().unwrap()[i].as_ref().unwrap().clone();
+        let peers = members.iter().map(|&i| self.addrs[i]).collect();
+        self.handle
+            .local_handle(self.addrs[i])
+            .spawn(async move { raft.change_membership(peers).await })
+            .await
+    }
+
+    /// Change the configuration to `members`.
+    pub async fn change_membership(&self, members: Vec<usize>) {
+        debug!("change_membership({:?})", members);
+        let peers = members.iter().map(|&i| self.addrs[i]).collect::<Vec<_>>();
+        self.reconfigure(|raft| {
+            let peers = peers.clone();
+            async move { raft.change_membership(peers).await }
+        })
+        .await;
+        *self.members.lock().unwrap() = members;
+    }
+
+    /// Start server i and add it to the cluster as a learner.
+    pub async fn add_learner(&self, i: usize) {
+        debug!("add_learner({})", i);
+        if !self.is_started(i) {
+            self.start1_ext(i, self.snapshot).await;
+            self.connect(i);
+        }
+        let addr = self.addrs[i];
+        self.reconfigure(|raft| async move { raft.add_learner(addr).await })
+            .await;
+    }
+
+    /// Promote the learner i to a voter.
+    pub async fn promote_learner(&self, i: usize) {
+        debug!("promote_learner({})", i);
+        let addr = self.addrs[i];
+        self.reconfigure(|raft| async move { raft.promote_learner(addr).await })
+            .await;
+        self.members.lock().unwrap().push(i);
+    }
+
+    /// Run a configuration change.
+    ///
+    /// Try all the servers until the leader completes the change.
+    /// Entirely gives up after about 10 seconds.
+    async fn reconfigure<F, Fut>(&self, change: F)
+    where
+        F: Fn(RaftHandle) -> Fut,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        let t0 = Instant::now();
+        while t0.elapsed() < Duration::from_secs(10) {
+            for i in 0..self.addrs.len() {
+                if !self.is_connected(i) || !self.is_started(i) {
+                    continue;
+                }
+                let raft = self.rafts.lock().unwrap()[i].as_ref().unwrap().clone();
+                let res = self
+                    .handle
+                    .local_handle(self.addrs[i])
+                    .spawn(change(raft))
+                    .await;
+                match res {
+                    Ok(()) => return,
+                    Err(e) => debug!("configuration change on {} failed: {:?}", i, e),
+                }
+            }
+            time::sleep(Duration::from_millis(50)).await;
+        }
+        panic!("configuration change failed");
+    }
+
     /// wait for at least n servers to commit.
     /// but don't wait forever.
     pub async fn wait(&self, index: u64, n: usize, start_term: Option<u64>) -> Option<Entry> {
@@ -220,8 +548,8 @@ impl RaftTester {
         while t0.elapsed() < Duration::from_secs(10) {
             // try all the servers, maybe one is the leader.
             let mut index = None;
-            for _ in 0..self.n {
-                starts = (starts + 1) % self.n;
+            for _ in 0..self.addrs.len() {
+                starts = (starts + 1) % self.addrs.len();
                 if !self.connected[starts].load(Ordering::SeqCst) || !self.is_started(starts) {
                     continue;
                 }
@@ -290,40 +618,210 @@ impl RaftTester {
         self.start1_ext(i, true).await;
     }
 
+    /// Start or re-start a Raft, returning the error if it fails to restore.
+    pub async fn try_start1(&self, i: usize) -> Result<()> {
+        self.try_start1_ext(i, self.snapshot).await
+    }
+
     async fn start1_ext(&self, i: usize, snapshot: bool) {
+        self.try_start1_ext(i, snapshot).await.unwrap();
+    }
+
+    async fn try_start1_ext(&self, i: usize, snapshot: bool) -> Result<()> {
         self.crash1(i);
 
-        let addrs = self.addrs.clone();
         let handle = self.handle.local_handle(self.addrs[i]);
-        let (raft, mut apply_recver) = handle.spawn(RaftHandle::new(addrs, i)).await;
+        let delay = self.disk_delays[i].clone();
+        let writes = self.disk_writes[i].clone();
+        let (raft, mut apply_recver) = match &self.mem_storage {
+            Some(storage) => {
+                let storage = SlowStorage::new(storage[i].clone(), delay, writes);
+                self.spawn_raft(&handle, i, storage).await?
+            }
+            None => {
+                let storage = SlowStorage::new(FsStorage::new(), delay, writes);
+                self.spawn_raft(&handle, i, storage).await?
+            }
+        };
         self.rafts.lock().unwrap()[i] = Some(raft.clone());
 
         // listen to messages from Raft indicating newly committed messages.
         let storage = self.storage.clone();
+        let apply_delay = self.apply_delays[i].clone();
         let task = handle.spawn(async move {
             while let Some(cmd) = apply_recver.next().await {
-                match cmd {
-                    ApplyMsg::Command { data, index } => {
-                        debug!("server {} apply {}", i, index);
-                        let entry =
-                            bincode::deserialize(&data).expect("committed command is not an entry");
-                        storage.push_and_check(i, index, entry);
-                        if snapshot && (index + 1) % SNAPSHOT_INTERVAL == 0 {
-                            raft.snapshot(index, &data).await.unwrap();
-                        }
+                // a slow service
+                let delay = *apply_delay.lock().unwrap();
+                if !delay.is_zero() {
+                    time::sleep(delay).await;
+                }
+                let entries = match cmd {
+                    ApplyMsg::Command { data, index } => vec![(index, data)],
+                    ApplyMsg::Commands { entries } => entries,
+                    ApplyMsg::Config { index, .. } => {
+                        storage.skip(i, index, &raft);
+                        continue;
                     }
                     ApplyMsg::Snapshot { data, index, term } if snapshot => {
                         // debug!("install snapshot {}", index);
                         if raft.cond_install_snapshot(term, index, &data).await {
                             storage.snapshot(i, index);
                         }
+                        continue;
                     }
                     // ignore other types of ApplyMsg
-                    _ => {}
+                    _ => continue,
+                };
+                for (index, data) in entries {
+                    debug!("server {} apply {}", i, index);
+                    let entry =
+                        bincode::deserialize(&data).expect("committed command is not an entry");
+                    storage.push_and_check(i, index, entry, &raft);
+                    if snapshot && (index + 1) % SNAPSHOT_INTERVAL == 0 {
+                        raft.snapshot(index, &data).await.unwrap();
+                    }
                 }
             }
         });
         task.detach();
+        Ok(())
+    }
+
+    async fn spawn_raft(
+        &self,
+        handle: &LocalHandle,
+        i: usize,
+        storage: impl Storage,
+    ) -> Result<(RaftHandle, MsgRecver)> {
+        let config = self.raft_config.clone();
+        if self.witness == Some(i) {
+            let addrs = self.addrs[..self.n].to_vec();
+            handle
+                .spawn(RaftHandle::new_witness(addrs, i, config, storage))
+                .await
+        } else if i < self.n {
+            let addrs = self.addrs[..self.n].to_vec();
+            handle
+                .spawn(RaftHandle::new(addrs, i, config, storage))
+                .await
+        } else {
+            // spare servers join the cluster later
+            handle
+                .spawn(RaftHandle::join(self.addrs[i], config, storage))
+                .await
+        }
+    }
+
+    /// Make each sync of server i's storage take `delay`.
+    pub fn set_disk_delay(&self, i: usize, delay: Duration) {
+        *self.disk_delays[i].lock().unwrap() = delay;
+    }
+
+    /// The number of bytes of log entries and hard state written to the
+    /// storage of all servers.
+    pub fn disk_writes(&self) -> u64 {
+        let writes = self.disk_writes.iter();
+        writes.map(|w| w.load(Ordering::SeqCst)).sum()
+    }
+
+    /// Make the service on server i take `delay` to apply each message.
+    pub fn set_apply_delay(&self, i: usize, delay: Duration) {
+        *self.apply_delays[i].lock().unwrap() = delay;
+    }
+
+    /// The index of the last entry applied by the service on server i.
+    pub fn applied(&self, i: usize) -> u64 {
+        self.storage.logs.lock().unwrap()[i].len() as u64 - 1
+    }
+
+    pub fn persist_stats(&self, i: usize) -> PersistStats {
+        self.rafts.lock().unwrap()[i]
+            .as_ref()
+            .unwrap()
+            .persist_stats()
+    }
+
+    /// Flip a bit in the last byte of both slots of `file` on server i.
+    pub async fn corrupt(&self, i: usize, file: &'static str) {
+        debug!("corrupt({}, {})", i, file);
+        let handle = self.handle.local_handle(self.addrs[i]);
+        handle
+            .spawn(async move {
+                for slot in 0..2 {
+                    let name = format!("{}-{}", file, slot);
+                    let mut data = fs::read(&name).await.unwrap_or_default();
+                    if let Some(byte) = data.last_mut() {
+                        *byte ^= 1;
+                        let f = fs::File::create(&name).await.unwrap();
+                        f.write_all_at(&data, 0).await.unwrap();
+                    }
+                }
+            })
+            .await;
+    }
+
+    /// Leave the state of server i as if it crashed while saving it.
+    pub async fn tear_state(&self, i: usize) {
+        debug!("tear_state({})", i);
+        let handle = self.handle.local_handle(self.addrs[i]);
+        handle.spawn(tear_state()).await.unwrap();
+    }
+
+    /// Append an incomplete record to the last segment of server i, as if it
+    /// crashed in the middle of a write.
+    pub async fn tear(&self, i: usize) {
+        debug!("tear({})", i);
+        let handle = self.handle.local_handle(self.addrs[i]);
+        handle
+            .spawn(async move {
+                let mut last = None;
+                for seq in 0.. {
+                    match fs::read(format!("wal-{}", seq)).await {
+                        Ok(data) if !data.is_empty() => last = Some((seq, data)),
+                        Ok(_) => {}
+                        Err(_) => break,
+                    }
+                }
+                let (seq, mut data) = last.expect("no segment");
+                data.extend(torn_record());
+                let f = fs::File::create(format!("wal-{}", seq)).await.unwrap();
+                f.write_all_at(&data, 0).await.unwrap();
+            })
+            .await;
+    }
+
+    /// Damage the length of the first record in a segment of server i which
+    /// is not the last one, and return the name of the segment.
+    pub async fn corrupt_segment(&self, i: usize) -> String {
+        debug!("corrupt_segment({})", i);
+        let handle = self.handle.local_handle(self.addrs[i]);
+        handle
+            .spawn(async move {
+                let mut segments = vec![];
+                for seq in 0.. {
+                    match fs::read(format!("wal-{}", seq)).await {
+                        Ok(data) if !data.is_empty() => segments.push((seq, data)),
+                        Ok(_) => {}
+                        Err(_) => break,
+                    }
+                }
+                assert!(segments.len() >= 2, "only one segment");
+                let (seq, mut data) = segments.swap_remove(0);
+                // the highest byte of the length, after the file header
+                data[11] ^= 0x80;
+                let name = format!("wal-{}", seq);
+                let f = fs::File::create(&name).await.unwrap();
+                f.write_all_at(&data, 0).await.unwrap();
+                name
+            })
+            .await
+    }
+
+    /// Delete all persisted state of server i.
+    pub async fn wipe(&self, i: usize) {
+        debug!("wipe({})", i);
+        let handle = self.handle.local_handle(self.addrs[i]);
+        handle.spawn(FsStorage::wipe()).await.unwrap();
     }
 
     pub fn crash1(&self, i: usize) {
@@ -338,6 +836,7 @@ impl RaftTester {
     /// Print the Passed message, and some performance numbers.
     pub fn end(&self) {
         self.check_timeout();
+        self.check_status();
 
         // real time
         let t = self.t0.elapsed();
@@ -358,6 +857,68 @@ impl RaftTester {
     }
 }
 
+/// A storage whose syncs take some time, to simulate a slow disk.
+///
+/// It also counts the bytes of log entries and hard state written.
+struct SlowStorage<S> {
+    inner: S,
+    delay: Arc<Mutex<Duration>>,
+    writes: Arc<AtomicU64>,
+}
+
+impl<S: Storage> SlowStorage<S> {
+    fn new(inner: S, delay: Arc<Mutex<Duration>>, writes: Arc<AtomicU64>) -> Self {
+        SlowStorage {
+            inner,
+            delay,
+            writes,
+        }
+    }
+
+    fn count(&self, value: &impl Serialize) {
+        let size = bincode::serialized_size(value).unwrap();
+        self.writes.fetch_add(size, Ordering::SeqCst);
+    }
+}
+
+impl<S: Storage> Storage for SlowStorage<S> {
+    fn load(&mut self) -> BoxFuture<'_, Result<RaftState>> {
+        self.inner.load()
+    }
+
+    fn save_hard_state(&mut self, state: HardState) -> BoxFuture<'_, io::Result<()>> {
+        self.count(&state);
+        self.inner.save_hard_state(state)
+    }
+
+    fn append(&mut self, index: u64, entries: Vec<LogEntry>) -> BoxFuture<'_, io::Result<()>> {
+        self.count(&entries);
+        self.inner.append(index, entries)
+    }
+
+    fn truncate(&mut self, index: u64) -> BoxFuture<'_, io::Result<()>> {
+        self.inner.truncate(index)
+    }
+
+    fn save_snapshot(&mut self, snapshot: Snapshot) -> BoxFuture<'_, io::Result<()>> {
+        self.inner.save_snapshot(snapshot)
+    }
+
+    fn sync(&mut self) -> BoxFuture<'_, io::Result<()>> {
+        let delay = *self.delay.lock().unwrap();
+        Box::pin(async move {
+            if !delay.is_zero() {
+                time::sleep(delay).await;
+            }
+            self.inner.sync().await
+        })
+    }
+
+    fn size(&self) -> usize {
+        self.inner.size()
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Entry {
     pub x: u64,
@@ -367,16 +928,23 @@ pub struct Entry {
 struct StorageHandle {
     /// copy of each server's committed entries
     logs: Arc<Mutex<Vec<Vec<Option<Entry>>>>>,
+    /// Indices skipped by a server which could not be checked to be no-ops,
+    /// with the server. No server may apply a command at them.
+    unchecked: Arc<Mutex<HashMap<u64, usize>>>,
 }
 
 impl StorageHandle {
     fn new(n: usize) -> Self {
         StorageHandle {
             logs: Arc::new(Mutex::new(vec![vec![None]; n])),
+            unchecked: Arc::new(Mutex::new(HashMap::new())),
         }
     }
 
-    fn push_and_check(&self, i: usize, index: u64, entry: Entry) {
+    fn push_and_check(&self, i: usize, index: u64, entry: Entry, raft: &RaftHandle) {
+        if let Some(j) = self.unchecked.lock().unwrap().get(&index) {
+            panic!("server {} skipped command {}", j, index);
+        }
         let mut logs = self.logs.lock().unwrap();
         for (j, log) in logs.iter().enumerate() {
             if let Some(Some(old)) = log.get(index as usize) {
@@ -388,11 +956,42 @@ impl StorageHandle {
                 );
             }
         }
-        let log = &mut logs[i];
-        if index as usize > log.len() {
-            panic!("server {} apply out of order {}", i, index);
-        } else if index as usize == log.len() {
-            log.push(Some(entry));
+        if index as usize >= logs[i].len() {
+            self.skip_noops(&mut logs, i, index, raft);
+            logs[i].push(Some(entry));
+        }
+    }
+
+    /// Record a committed entry which is not a command.
+    fn skip(&self, i: usize, index: u64, raft: &RaftHandle) {
+        let mut logs = self.logs.lock().unwrap();
+        if index as usize >= logs[i].len() {
+            self.skip_noops(&mut logs, i, index, raft);
+            logs[i].push(None);
+        }
+    }
+
+    /// Record the entries before `index` that server i did not deliver, which
+    /// must be no-ops.
+    fn skip_noops(&self, logs: &mut [Vec<Option<Entry>>], i: usize, index: u64, raft: &RaftHandle) {
+        let mut unchecked = self.unchecked.lock().unwrap();
+        for skipped in logs[i].len() as u64..index {
+            match raft.is_noop(skipped) {
+                Some(true) => {}
+                Some(false) => panic!("server {} apply out of order {}", i, index),
+                // compacted by a snapshot installed since, so no server may
+                // have applied or apply a command there
+                None => {
+                    let applied = logs
+                        .iter()
+                        .any(|log| matches!(log.get(skipped as usize), Some(Some(_))));
+                    if applied {
+                        panic!("server {} skipped command {}", i, skipped);
+                    }
+                    unchecked.insert(skipped, i);
+                }
+            }
+            logs[i].push(None);
         }
     }
 
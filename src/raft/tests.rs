@@ -1,11 +1,12 @@
-use super::tester::*;
-use futures::future;
+use super::{tester::*, typed, Committed, Error, MemStorage, RaftConfig, RaftHandle, Role, Status};
+use futures::{future, StreamExt};
 use log::*;
 use madsim::{
     rand::{self, Rng},
     task, time,
 };
 use std::{
+    net::SocketAddr,
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc,
@@ -111,14 +112,197 @@ async fn many_election_2a() {
     t.end();
 }
 
+#[madsim::test]
+async fn prevote_2a() {
+    let servers = 3;
+    let t = RaftTester::new(servers).await;
+
+    info!("Test (2A): rejoining node does not raise the term");
+
+    let leader1 = t.check_one_leader().await;
+    let term1 = t.check_terms();
+
+    // an isolated follower can not win a pre-vote, so it should stay in term.
+    let follower = (leader1 + 1) % servers;
+    t.disconnect(follower);
+    time::sleep(5 * RAFT_ELECTION_TIMEOUT).await;
+    assert_eq!(t.term(follower), term1, "isolated follower raised its term");
+
+    // when it rejoins, the leader should not be deposed.
+    t.connect(follower);
+    time::sleep(RAFT_ELECTION_TIMEOUT).await;
+    assert_eq!(t.check_one_leader().await, leader1, "leader was deposed");
+    assert_eq!(t.check_terms(), term1, "term changed after rejoin");
+
+    // candidates that can not reach a quorum should stay in term.
+    t.disconnect((leader1 + 1) % servers);
+    t.disconnect((leader1 + 2) % servers);
+    time::sleep(5 * RAFT_ELECTION_TIMEOUT).await;
+    for i in 0..servers {
+        assert_eq!(t.term(i), term1, "server {} raised its term", i);
+    }
+
+    t.connect((leader1 + 1) % servers);
+    t.connect((leader1 + 2) % servers);
+    t.check_one_leader().await;
+
+    t.end();
+}
+
+#[madsim::test]
+async fn check_quorum_2a() {
+    let servers = 5;
+    let config = RaftConfig {
+        check_quorum: true,
+        ..raft_config()
+    };
+    let t = RaftTester::new_with_config(servers, false, config).await;
+
+    info!("Test (2A): isolated leader steps down");
+
+    let leader1 = t.check_one_leader().await;
+
+    // a leader in a minority steps down.
+    t.disconnect(leader1);
+    t.disconnect((leader1 + 1) % servers);
+    time::sleep(RAFT_ELECTION_TIMEOUT).await;
+    assert!(!t.is_leader(leader1), "isolated leader did not step down");
+    let leader2 = t.check_one_leader().await;
+    let term2 = t.check_terms();
+
+    // the old leader rejoins without disturbing the new one.
+    t.connect(leader1);
+    t.connect((leader1 + 1) % servers);
+    time::sleep(RAFT_ELECTION_TIMEOUT).await;
+    assert_eq!(t.check_one_leader().await, leader2, "leader was deposed");
+    assert_eq!(t.check_terms(), term2, "term changed after rejoin");
+
+    // the leader keeps its leadership with a bare quorum.
+    t.disconnect((leader2 + 1) % servers);
+    t.disconnect((leader2 + 2) % servers);
+    time::sleep(2 * RAFT_ELECTION_TIMEOUT).await;
+    assert!(t.is_leader(leader2), "leader with a quorum stepped down");
+
+    t.end();
+}
+
+#[madsim::test]
+async fn raft_config_2a() {
+    info!("Test (2A): invalid configs are rejected");
+
+    RaftConfig::default()
+        .validate()
+        .expect("default config is invalid");
+    let ms = Duration::from_millis;
+    let invalid = vec![
+        RaftConfig {
+            election_timeout: ms(300)..ms(150),
+            ..RaftConfig::default()
+        },
+        RaftConfig {
+            heartbeat_interval: ms(75),
+            ..RaftConfig::default()
+        },
+        RaftConfig {
+            heartbeat_interval: ms(0),
+            ..RaftConfig::default()
+        },
+        RaftConfig {
+            max_entries_per_rpc: 0,
+            ..RaftConfig::default()
+        },
+        RaftConfig {
+            max_inflight: 0,
+            ..RaftConfig::default()
+        },
+        RaftConfig {
+            snapshot_chunk_size: 0,
+            ..RaftConfig::default()
+        },
+        RaftConfig {
+            rpc_timeout: ms(0),
+            ..RaftConfig::default()
+        },
+        RaftConfig {
+            witness_max_log: 0,
+            ..RaftConfig::default()
+        },
+        RaftConfig {
+            max_catch_up_rounds: 0,
+            ..RaftConfig::default()
+        },
+        RaftConfig {
+            lease_read: Some(ms(150)),
+            ..RaftConfig::default()
+        },
+        RaftConfig {
+            apply_capacity: Some(0),
+            ..RaftConfig::default()
+        },
+    ];
+    for config in invalid {
+        let addr = "0.0.0.0:0".parse().unwrap();
+        let res = RaftHandle::new(vec![addr], 0, config.clone(), MemStorage::new()).await;
+        assert!(
+            matches!(res, Err(Error::InvalidConfig(_))),
+            "{:?} is accepted",
+            config
+        );
+    }
+}
+
+#[madsim::test]
+async fn leader_hint_2a() {
+    let servers = 3;
+    let t = RaftTester::new(servers).await;
+
+    info!("Test (2A): followers know the leader");
+
+    let leader1 = t.check_one_leader().await;
+    // let followers hear from the leader
+    time::sleep(RAFT_ELECTION_TIMEOUT / 2).await;
+    for i in 0..servers {
+        assert_eq!(t.leader(i), Some(leader1), "server {} lost the leader", i);
+    }
+    let follower = (leader1 + 1) % servers;
+    match t.start(follower, Entry { x: 1 }).await {
+        Err(Error::NotLeader(hint)) => assert_eq!(hint, Some(leader1)),
+        res => panic!("start on a follower returned {:?}", res),
+    }
+
+    let mut roles = t.watch_role(follower);
+    let (term1, role, leader) = roles.next().await.unwrap();
+    assert_eq!((role, leader), (Role::Follower, Some(leader1)));
+
+    // the follower learns of the new leader
+    t.disconnect(leader1);
+    let leader2 = t.check_one_leader().await;
+    time::sleep(RAFT_ELECTION_TIMEOUT / 2).await;
+    let mut changes = vec![];
+    while let Ok(change) = roles.try_recv() {
+        changes.push(change);
+    }
+    let (term2, _, leader) = *changes.last().expect("no role change");
+    assert!(term2 > term1, "term did not change: {:?}", changes);
+    assert_eq!(leader, Some(leader2));
+
+    // so does the old leader once reconnected
+    t.connect(leader1);
+    time::sleep(RAFT_ELECTION_TIMEOUT / 2).await;
+    assert_eq!(t.leader(leader1), Some(leader2));
+
+    t.end();
+}
+
 #[madsim::test]
 async fn basic_agree_2b() {
     let servers = 5;
     let t = RaftTester::new(servers).await;
     info!("Test (2B): basic agreement");
 
+    // index 1 holds the no-op of the leader
     let iters = 3;
-    for index in 1..=iters {
+    for index in 2..=iters + 1 {
         let (nd, _) = t.n_committed(index);
         assert_eq!(nd, 0, "some have committed before start()");
 
@@ -179,8 +363,8 @@ async fn fail_no_agree_2b() {
         .await
         .expect("leader rejected start")
         .index;
-    if index != 2 {
-        panic!("expected index 2, got {}", index);
+    if index != 3 {
+        panic!("expected index 3, got {}", index);
     }
 
     time::sleep(2 * RAFT_ELECTION_TIMEOUT).await;
@@ -194,14 +378,15 @@ async fn fail_no_agree_2b() {
     t.connect((leader + 3) % servers);
 
     // the disconnected majority may have chosen a leader from
-    // among their own ranks, forgetting index 2.
+    // among their own ranks, forgetting index 3.
     let leader2 = t.check_one_leader().await;
     let index2 = t
         .start(leader2, Entry { x: 30 })
         .await
         .expect("leader2 rejected start")
         .index;
-    assert!((2..=3).contains(&index2), "unexpected index {}", index2);
+    // a new leader appends a no-op first.
+    assert!((4..=5).contains(&index2), "unexpected index {}", index2);
 
     t.one(Entry { x: 1000 }, servers, true).await;
 
@@ -479,195 +664,883 @@ async fn count_2b() {
 }
 
 #[madsim::test]
-async fn persist1_2c() {
+async fn status_2b() {
     let servers = 3;
     let t = RaftTester::new(servers).await;
 
-    info!("Test (2C): basic persistence");
-
-    t.one(Entry { x: 11 }, servers, true).await;
+    info!("Test (2B): status of peers");
 
-    // crash and re-start all
-    for i in 0..servers {
-        t.start1(i).await;
-    }
-    for i in 0..servers {
-        t.disconnect(i);
-        t.connect(i);
+    let mut random = rand::rng();
+    let index = t.one(random.gen_entry(), servers, true).await;
+    let leader = t.check_one_leader().await;
+    // let followers learn the commit index
+    time::sleep(RAFT_ELECTION_TIMEOUT / 2).await;
+    t.check_status();
+
+    let status = t.status(leader);
+    assert_eq!(status.role, Role::Leader);
+    assert_eq!(status.leader, Some(leader));
+    assert_eq!(status.commit_index, index);
+    assert_eq!(status.progress.len(), servers - 1);
+    for p in &status.progress {
+        assert_eq!((p.match_index, p.next_index), (index, index + 1));
     }
+    let follower = (leader + 1) % servers;
+    let status = t.status(follower);
+    assert_eq!((status.role, status.leader), (Role::Follower, Some(leader)));
+    assert_eq!(status.commit_index, index);
+    assert!(status.progress.is_empty());
 
-    t.one(Entry { x: 12 }, servers, true).await;
-
-    let leader1 = t.check_one_leader().await;
-    t.disconnect(leader1);
-    t.start1(leader1).await;
-    t.connect(leader1);
+    // status is serializable and stable when nothing happens
+    let bytes = bincode::serialize(&status).unwrap();
+    let decoded: Status = bincode::deserialize(&bytes).unwrap();
+    assert_eq!(decoded, t.status(follower));
 
-    t.one(Entry { x: 13 }, servers, true).await;
+    t.end();
+}
 
-    let leader2 = t.check_one_leader().await;
-    t.disconnect(leader2);
-    t.one(Entry { x: 14 }, servers - 1, true).await;
-    t.start1(leader2).await;
-    t.connect(leader2);
+#[madsim::test]
+async fn group_commit_2b() {
+    let servers = 3;
+    let t = RaftTester::new(servers).await;
 
-    // wait for leader2 to join before killing i3
-    t.wait(4, servers, None).await;
+    info!("Test (2B): group commit of concurrent persists");
 
-    let i3 = (t.check_one_leader().await + 1) % servers;
-    t.disconnect(i3);
-    t.one(Entry { x: 15 }, servers - 1, true).await;
-    t.start1(i3).await;
-    t.connect(i3);
+    let mut random = rand::rng();
+    t.one(random.gen_entry(), servers, true).await;
+    for i in 0..servers {
+        t.set_disk_delay(i, Duration::from_millis(10));
+    }
+    let leader = t.check_one_leader().await;
+    let before = t.persist_stats(leader);
 
-    t.one(Entry { x: 16 }, servers, true).await;
+    let starts = (0..50).map(|_| t.start(leader, random.gen_entry()));
+    let indices = future::join_all(starts)
+        .await
+        .into_iter()
+        .map(|start| start.expect("leader lost leadership").index)
+        .collect::<Vec<_>>();
+    t.wait(*indices.iter().max().unwrap(), servers, None).await;
+
+    let stats = t.persist_stats(leader);
+    let requests = stats.requests - before.requests;
+    let syncs = stats.syncs - before.syncs;
+    info!(
+        "  leader persisted {} requests in {} syncs",
+        requests, syncs
+    );
+    assert!(
+        syncs * 5 < requests,
+        "too many syncs: {} syncs for {} requests",
+        syncs,
+        requests
+    );
 
     t.end();
 }
 
 #[madsim::test]
-async fn persist2_2c() {
-    let servers = 5;
-    let t = RaftTester::new(servers).await;
-
-    info!("Test (2C): more persistence");
-
-    let mut index = 1;
-    for _ in 0..5 {
-        t.one(Entry { x: 10 + index }, servers, true).await;
-        index += 1;
+async fn slow_leader_disk_2b() {
+    let servers = 3;
+    let t = Arc::new(RaftTester::new(servers).await);
 
-        let leader1 = t.check_one_leader().await;
+    info!("Test (2B): leader persists in parallel with replication");
 
-        t.disconnect((leader1 + 1) % servers);
-        t.disconnect((leader1 + 2) % servers);
+    let mut random = rand::rng();
+    let index = t.one(random.gen_entry(), servers, true).await + 1;
+    let leader = t.check_one_leader().await;
+    let delay = Duration::from_millis(500);
+    t.set_disk_delay(leader, delay);
+
+    // followers commit the entry before the leader's disk syncs
+    let t0 = time::Instant::now();
+    let t1 = t.clone();
+    let entry = random.gen_entry();
+    let start = task::spawn(async move { t1.start(leader, entry).await });
+    t.wait(index, servers - 1, None).await;
+    assert!(
+        t0.elapsed() < delay,
+        "commit waited for the leader's disk ({:?})",
+        t0.elapsed()
+    );
+    start.await.expect("leader lost leadership");
+    assert_eq!(t.wait(index, servers, None).await, Some(entry));
+
+    // with a follower down, the entry commits only after the leader's disk
+    // syncs
+    let follower = (leader + 1) % servers;
+    t.disconnect(follower);
+    let t1 = t.clone();
+    let entry = random.gen_entry();
+    let start = task::spawn(async move { t1.start(leader, entry).await });
+    time::sleep(delay / 2).await;
+    assert_eq!(t.n_committed(index + 1).0, 0, "committed before persisted");
+    start.await.expect("leader lost leadership");
+    assert_eq!(t.wait(index + 1, servers - 1, None).await, Some(entry));
+    t.connect(follower);
 
-        t.one(Entry { x: 10 + index }, servers - 2, true).await;
-        index += 1;
+    t.end();
+}
 
-        t.disconnect((leader1 + 0) % servers);
-        t.disconnect((leader1 + 3) % servers);
-        t.disconnect((leader1 + 4) % servers);
+#[madsim::test]
+async fn pipeline_unreliable_2b() {
+    pipeline_unreliable(8).await;
+}
 
-        t.start1((leader1 + 1) % servers).await;
-        t.start1((leader1 + 2) % servers).await;
-        t.connect((leader1 + 1) % servers);
-        t.connect((leader1 + 2) % servers);
+#[madsim::test]
+async fn pipeline_stop_and_wait_unreliable_2b() {
+    pipeline_unreliable(1).await;
+}
 
-        time::sleep(RAFT_ELECTION_TIMEOUT).await;
+async fn pipeline_unreliable(window: usize) {
+    let servers = 3;
+    let config = RaftConfig {
+        max_inflight: window,
+        ..raft_config()
+    };
+    let t = RaftTester::new_with_config(servers, false, config).await;
+    t.set_unreliable(true);
 
-        t.start1((leader1 + 3) % servers).await;
-        t.connect((leader1 + 3) % servers);
+    info!("Test (2B): pipelined replication on an unreliable network");
 
-        t.one(Entry { x: 10 + index }, servers - 2, true).await;
-        index += 1;
+    let mut random = rand::rng();
+    t.one(random.gen_entry(), servers, true).await;
+    for _ in 0..2 {
+        let leader = t.check_one_leader().await;
+        let total1 = t.rpc_total();
+        let t0 = time::Instant::now();
 
-        t.connect((leader1 + 4) % servers);
-        t.connect((leader1 + 0) % servers);
+        let iters = 200;
+        let starts = (0..iters).map(|_| t.start(leader, random.gen_entry()));
+        let starts = future::join_all(starts).await;
+        let term = match &starts[0] {
+            Ok(start) => start.term,
+            Err(_) => continue,
+        };
+        let last = match starts
+            .iter()
+            .map(|s| s.as_ref().ok())
+            .collect::<Option<Vec<_>>>()
+        {
+            Some(starts) if starts.iter().all(|s| s.term == term) => {
+                starts.iter().map(|s| s.index).max().unwrap()
+            }
+            // leader changed while starting
+            _ => continue,
+        };
+        t.wait(last, servers, Some(term)).await;
+        let rpcs = t.rpc_total() - total1;
+        info!(
+            "  window {}: {} entries committed in {:?} with {} RPCs",
+            window,
+            iters,
+            t0.elapsed(),
+            rpcs
+        );
+        assert!(
+            rpcs < iters,
+            "entries are not batched: {} RPCs for {} entries",
+            rpcs,
+            iters
+        );
     }
 
-    t.one(Entry { x: 1000 }, servers, true).await;
-
     t.end();
 }
 
 #[madsim::test]
-async fn persist3_2c() {
+async fn propose_2b() {
     let servers = 3;
-    let t = RaftTester::new(servers).await;
+    let t = Arc::new(RaftTester::new(servers).await);
 
-    info!("Test (2C): partitioned leader and one follower crash, leader restarts");
+    info!("Test (2B): proposals resolve on commit or loss");
 
-    t.one(Entry { x: 101 }, 3, true).await;
+    let mut random = rand::rng();
+    t.one(random.gen_entry(), servers, true).await;
 
+    // a proposal on the leader resolves once committed
     let leader = t.check_one_leader().await;
-    t.disconnect((leader + 2) % servers);
+    let entry = random.gen_entry();
+    let Committed(index) = t.propose(leader, entry).await.expect("proposal failed");
+    assert_eq!(t.wait(index, servers, None).await, Some(entry));
+
+    // a proposal on a follower fails at once
+    let follower = (leader + 1) % servers;
+    assert!(matches!(
+        t.propose(follower, random.gen_entry()).await,
+        Err(Error::NotLeader(_))
+    ));
+
+    // a proposal on a partitioned leader fails after it learns of a new leader
+    t.disconnect(leader);
+    let t1 = t.clone();
+    let entry = random.gen_entry();
+    let proposal = task::spawn(async move { t1.propose(leader, entry).await });
+    for _ in 0..3 {
+        t.one(random.gen_entry(), servers - 1, true).await;
+    }
+    t.connect(leader);
+    assert!(matches!(proposal.await, Err(Error::LeadershipLost)));
+    t.one(random.gen_entry(), servers, true).await;
 
-    t.one(Entry { x: 102 }, 2, true).await;
+    t.end();
+}
 
-    t.crash1((leader + 0) % servers);
-    t.crash1((leader + 1) % servers);
-    t.connect((leader + 2) % servers);
-    t.start1((leader + 0) % servers).await;
-    t.connect((leader + 0) % servers);
+#[madsim::test]
+async fn forward_proposals_2b() {
+    let servers = 3;
+    let config = RaftConfig {
+        forward_proposals: true,
+        ..raft_config()
+    };
+    let t = RaftTester::new_with_config(servers, false, config).await;
 
-    t.one(Entry { x: 103 }, 2, true).await;
+    info!("Test (2B): followers forward proposals to the leader");
 
-    t.start1((leader + 1) % servers).await;
-    t.connect((leader + 1) % servers);
+    let mut random = rand::rng();
+    t.one(random.gen_entry(), servers, true).await;
 
-    t.one(Entry { x: 104 }, servers, true).await;
+    // a follower returns where the leader appended the command
+    let leader = t.check_one_leader().await;
+    let follower = (leader + 1) % servers;
+    let entry = random.gen_entry();
+    let start = t.start(follower, entry).await.expect("start failed");
+    assert_eq!(t.term(leader), start.term);
+    assert_eq!(t.wait(start.index, servers, None).await, Some(entry));
+
+    // and resolves a proposal once it applies the command
+    let entry = random.gen_entry();
+    let Committed(index) = t.propose(follower, entry).await.expect("proposal failed");
+    assert!(index > start.index);
+    assert_eq!(t.n_committed(index).1, Some(entry));
+
+    // a follower cut off from the leader can not forward
+    t.disconnect(follower);
+    assert!(matches!(
+        t.start(follower, random.gen_entry()).await,
+        Err(Error::NotLeader(_))
+    ));
+    t.connect(follower);
+    t.one(random.gen_entry(), servers, true).await;
 
     t.end();
 }
 
-/// Test the scenarios described in Figure 8 of the extended Raft paper. Each
-/// iteration asks a leader, if there is one, to insert a command in the Raft
-/// log.  If there is a leader, that leader will fail quickly with a high
-/// probability (perhaps without committing the command), or crash after a while
-/// with low probability (most likey committing the command).  If the number of
-/// alive servers isn't enough to form a majority, perhaps start a new server.
-/// The leader in a new term may try to finish replicating log entries that
-/// haven't been committed yet.
 #[madsim::test]
-async fn figure_8_2c() {
-    let servers = 5;
-    let t = RaftTester::new(servers).await;
+async fn slow_apply_2b() {
+    let servers = 3;
+    let cap = 4;
+    let config = RaftConfig {
+        apply_capacity: Some(cap),
+        ..raft_config()
+    };
+    let t = RaftTester::new_with_config(servers, false, config).await;
 
-    info!("Test (2C): Figure 8");
+    info!("Test (2B): bounded apply channel with a slow service");
 
     let mut random = rand::rng();
-    t.one(random.gen_entry(), 1, true).await;
-
-    let mut nup = servers;
-    for _iters in 0..1000 {
-        let mut leader = None;
-        for i in 0..servers {
-            if t.is_started(i) && t.start(i, random.gen_entry()).await.is_ok() {
-                leader = Some(i);
-            }
-        }
-
-        let delay = if random.gen_bool(0.1) {
-            random.gen_range(Duration::from_millis(0)..RAFT_ELECTION_TIMEOUT / 2)
-        } else {
-            random.gen_range(Duration::from_millis(0)..Duration::from_millis(13))
-        };
-        time::sleep(delay).await;
-
-        if let Some(leader) = leader {
-            t.crash1(leader);
-            nup -= 1;
-        }
+    t.one(random.gen_entry(), servers, true).await;
+    let leader = t.check_one_leader().await;
 
-        if nup < 3 {
-            let s = random.gen_range(0..servers);
-            if !t.is_started(s) {
-                t.start1(s).await;
-                nup += 1;
+    // a slow follower does not buffer more than the capacity
+    let slow = (leader + 1) % servers;
+    t.set_apply_delay(slow, Duration::from_millis(20));
+    let mut last = 0;
+    for _ in 0..50 {
+        let entry = random.gen_entry();
+        last = t.start(leader, entry).await.expect("start failed").index;
+        time::sleep(Duration::from_millis(5)).await;
+        let queued = t.status(slow).last_applied - t.applied(slow);
+        // plus the message being applied
+        assert!(queued <= cap as u64 + 1, "{} messages queued", queued);
+    }
+    t.wait(last, servers, None).await;
+
+    // the leader rejects proposals when it falls behind
+    t.set_apply_delay(leader, Duration::from_secs(1));
+    let mut rejected = false;
+    for _ in 0..50 {
+        match t.start(leader, random.gen_entry()).await {
+            Ok(_) => time::sleep(Duration::from_millis(10)).await,
+            Err(Error::ApplyBacklog) => {
+                rejected = true;
+                break;
             }
+            Err(e) => panic!("start failed: {:?}", e),
         }
     }
+    assert!(rejected, "proposals were not rejected");
+    let status = t.status(leader);
+    assert!(status.commit_index - status.last_applied >= cap as u64);
 
-    for i in 0..servers {
-        if !t.is_started(i) {
-            t.start1(i).await;
-        }
-    }
+    // and accepts them again once the service catches up
+    t.set_apply_delay(leader, Duration::ZERO);
     t.one(random.gen_entry(), servers, true).await;
 
     t.end();
 }
 
 #[madsim::test]
-async fn unreliable_agree_2c() {
-    let servers = 5;
+async fn batch_apply_2b() {
+    let servers = 3;
+    let config = RaftConfig {
+        batch_apply: true,
+        ..raft_config()
+    };
+    let t = RaftTester::new_with_config(servers, true, config).await;
 
-    let t = Arc::new(RaftTester::new(servers).await);
-    info!("Test (2C): unreliable agreement");
+    info!("Test (2B): batched apply messages");
 
-    t.set_unreliable(true);
-    let mut dones = vec![];
+    let mut random = rand::rng();
+    t.one(random.gen_entry(), servers, true).await;
+    let leader = t.check_one_leader().await;
+    let slow = (leader + 1) % servers;
+    let delay = Duration::from_millis(20);
+    t.set_apply_delay(slow, delay);
+
+    let iters = 50;
+    let mut last = 0;
+    let t0 = time::Instant::now();
+    for _ in 0..iters {
+        last = t
+            .start(leader, random.gen_entry())
+            .await
+            .expect("start failed")
+            .index;
+    }
+    t.wait(last, servers, None).await;
+    while t.applied(slow) < last {
+        time::sleep(Duration::from_millis(10)).await;
+    }
+    // the slow service receives commands in a few batches
+    assert!(
+        t0.elapsed() < delay * iters / 4,
+        "commands are not batched: applied in {:?}",
+        t0.elapsed()
+    );
+    t.one(random.gen_entry(), servers, true).await;
+
+    t.end();
+}
+
+#[madsim::test]
+async fn typed_commands_2b() {
+    info!("Test (2B): typed commands round trip");
+
+    let handle = madsim::Handle::current();
+    for (i, &batch_apply) in [false, true].iter().enumerate() {
+        let addr = SocketAddr::from(([0, 0, 2, i as _], 0));
+        let config = RaftConfig {
+            batch_apply,
+            ..raft_config()
+        };
+        let task = handle.local_handle(addr).spawn(async move {
+            let (rf, mut apply_ch) =
+                typed::RaftHandle::<(u64, String)>::new(vec![addr], 0, config, MemStorage::new())
+                    .await
+                    .unwrap();
+            let cmd1 = (1, "start".to_string());
+            let start = loop {
+                match rf.start(&cmd1).await {
+                    Ok(start) => break start,
+                    Err(Error::NotLeader(_)) => time::sleep(RAFT_ELECTION_TIMEOUT / 10).await,
+                    Err(e) => panic!("start failed: {}", e),
+                }
+            };
+            let cmd2 = (2, "propose".to_string());
+            let Committed(index) = rf.propose(&cmd2).await.expect("proposal failed");
+
+            let mut applied = vec![];
+            let mut batched = false;
+            while applied.len() < 2 {
+                match apply_ch.next().await.unwrap() {
+                    typed::ApplyMsg::Command { cmd, index } => applied.push((index, cmd)),
+                    typed::ApplyMsg::Commands { entries } => {
+                        batched = true;
+                        applied.extend(entries);
+                    }
+                    _ => {}
+                }
+            }
+            assert_eq!(applied, vec![(start.index, cmd1), (index, cmd2)]);
+            assert_eq!(batched, batch_apply);
+        });
+        task.await;
+    }
+}
+
+#[madsim::test]
+async fn read_index_2b() {
+    let servers = 3;
+    let t = RaftTester::new(servers).await;
+
+    info!("Test (2B): read index");
+
+    let index = t.one(Entry { x: 101 }, servers, false).await;
+
+    // the leader confirms its leadership and returns its commit index.
+    let leader1 = t.check_one_leader().await;
+    let read_index = t.read_index(leader1).await.expect("leader rejected read");
+    assert!(read_index >= index, "read index {} < {}", read_index, index);
+    t.read_index((leader1 + 1) % servers)
+        .await
+        .expect_err("follower accepted read");
+
+    // a partitioned leader can not confirm its leadership.
+    t.disconnect(leader1);
+    t.read_index(leader1)
+        .await
+        .expect_err("partitioned leader accepted read");
+
+    // the new leader serves reads once it commits an entry in its term.
+    let index = t.one(Entry { x: 102 }, servers - 1, true).await;
+    let leader2 = t.check_one_leader().await;
+    let read_index = t.read_index(leader2).await.expect("leader rejected read");
+    assert!(read_index >= index, "read index {} < {}", read_index, index);
+
+    t.connect(leader1);
+    t.one(Entry { x: 103 }, servers, true).await;
+
+    t.end();
+}
+
+#[madsim::test]
+async fn leader_noop_2b() {
+    let servers = 3;
+    let t = RaftTester::new(servers).await;
+
+    info!("Test (2B): leader commits a no-op on election");
+
+    // a new leader serves reads without any command, and the no-op is not
+    // delivered to the service.
+    let leader = t.check_one_leader().await;
+    let read_index = t.read_index(leader).await.expect("leader rejected read");
+    assert_eq!(read_index, 0, "read index of an empty log");
+    let status = t.status(leader);
+    assert!(status.commit_index > 0, "no-op not committed");
+    for i in 0..servers {
+        assert_eq!(t.applied(i), 0, "no-op delivered to server {}", i);
+    }
+
+    // an entry of an earlier term commits once the next leader is elected.
+    t.disconnect((leader + 1) % servers);
+    t.disconnect((leader + 2) % servers);
+    let index = t
+        .start(leader, Entry { x: 101 })
+        .await
+        .expect("leader rejected start")
+        .index;
+    t.crash1(leader);
+    t.start1(leader).await;
+    t.connect(leader);
+    t.connect((leader + 1) % servers);
+    assert_eq!(
+        t.wait(index, servers - 1, None).await,
+        Some(Entry { x: 101 }),
+        "entry of an earlier term not committed"
+    );
+
+    t.connect((leader + 2) % servers);
+    t.one(Entry { x: 102 }, servers, true).await;
+
+    t.end();
+}
+
+#[madsim::test]
+async fn lease_read_2b() {
+    let servers = 5;
+    let max_drift = Duration::from_millis(30);
+    let config = RaftConfig {
+        lease_read: Some(max_drift),
+        ..raft_config()
+    };
+    let t = Arc::new(RaftTester::new_with_config(servers, false, config).await);
+
+    info!("Test (2B): lease read with clock drift");
+    // clocks drift within the bound over an election timeout.
+    for i in 0..servers {
+        t.set_clock_rate(i, 1.0 + 0.04 * (i % 3) as f64);
+    }
+
+    let mut random = rand::rng();
+    for _ in 0..5 {
+        t.one(random.gen_entry(), servers, true).await;
+        let leader = t.check_one_leader().await;
+        // a follower cut off from the leader keeps campaigning.
+        let candidate = (leader + 1) % servers;
+        t.disconnect(candidate);
+        time::sleep(RAFT_ELECTION_TIMEOUT).await;
+
+        // the leader serves reads locally while its lease is valid.
+        let rpcs = t.rpc_total();
+        let index = t.read_index(leader).await.expect("leader rejected read");
+        assert_eq!(t.rpc_total(), rpcs, "lease read sent RPCs");
+        assert!(index >= t.max_committed(), "lease read is stale");
+
+        // followers restarted during the lease do not elect the candidate
+        // before it expires.
+        for i in 2..servers / 2 + 2 {
+            let follower = (leader + i) % servers;
+            t.crash1(follower);
+            t.start1(follower).await;
+            t.connect(follower);
+        }
+
+        // partition the leader while the others keep committing.
+        t.disconnect(leader);
+        t.connect(candidate);
+        let stop = Arc::new(AtomicBool::new(false));
+        let writer = {
+            let t = t.clone();
+            let stop = stop.clone();
+            task::spawn_local(async move {
+                let mut random = rand::rng();
+                while !stop.load(Ordering::SeqCst) {
+                    t.one(random.gen_entry(), servers - 1, true).await;
+                }
+            })
+        };
+        // the old leader must not serve stale reads.
+        for _ in 0..100 {
+            if let Ok(index) = t.read_index(leader).await {
+                let committed = t.max_committed();
+                assert!(
+                    index >= committed,
+                    "stale read at {} after {} committed",
+                    index,
+                    committed
+                );
+            }
+            time::sleep(Duration::from_millis(5)).await;
+        }
+        stop.store(true, Ordering::SeqCst);
+        writer.await;
+        t.connect(leader);
+    }
+
+    t.one(random.gen_entry(), servers, true).await;
+
+    t.end();
+}
+
+#[madsim::test]
+async fn persist1_2c() {
+    let servers = 3;
+    let t = RaftTester::new(servers).await;
+
+    info!("Test (2C): basic persistence");
+
+    t.one(Entry { x: 11 }, servers, true).await;
+
+    // crash and re-start all
+    for i in 0..servers {
+        t.start1(i).await;
+    }
+    for i in 0..servers {
+        t.disconnect(i);
+        t.connect(i);
+    }
+
+    t.one(Entry { x: 12 }, servers, true).await;
+
+    let leader1 = t.check_one_leader().await;
+    t.disconnect(leader1);
+    t.start1(leader1).await;
+    t.connect(leader1);
+
+    t.one(Entry { x: 13 }, servers, true).await;
+
+    let leader2 = t.check_one_leader().await;
+    t.disconnect(leader2);
+    t.one(Entry { x: 14 }, servers - 1, true).await;
+    t.start1(leader2).await;
+    t.connect(leader2);
+
+    // wait for leader2 to join before killing i3
+    t.wait(4, servers, None).await;
+
+    let i3 = (t.check_one_leader().await + 1) % servers;
+    t.disconnect(i3);
+    t.one(Entry { x: 15 }, servers - 1, true).await;
+    t.start1(i3).await;
+    t.connect(i3);
+
+    t.one(Entry { x: 16 }, servers, true).await;
+
+    t.end();
+}
+
+#[madsim::test]
+async fn persist2_2c() {
+    let servers = 5;
+    let t = RaftTester::new(servers).await;
+
+    info!("Test (2C): more persistence");
+
+    let mut index = 1;
+    for _ in 0..5 {
+        t.one(Entry { x: 10 + index }, servers, true).await;
+        index += 1;
+
+        let leader1 = t.check_one_leader().await;
+
+        t.disconnect((leader1 + 1) % servers);
+        t.disconnect((leader1 + 2) % servers);
+
+        t.one(Entry { x: 10 + index }, servers - 2, true).await;
+        index += 1;
+
+        t.disconnect((leader1 + 0) % servers);
+        t.disconnect((leader1 + 3) % servers);
+        t.disconnect((leader1 + 4) % servers);
+
+        t.start1((leader1 + 1) % servers).await;
+        t.start1((leader1 + 2) % servers).await;
+        t.connect((leader1 + 1) % servers);
+        t.connect((leader1 + 2) % servers);
+
+        time::sleep(RAFT_ELECTION_TIMEOUT).await;
+
+        t.start1((leader1 + 3) % servers).await;
+        t.connect((leader1 + 3) % servers);
+
+        t.one(Entry { x: 10 + index }, servers - 2, true).await;
+        index += 1;
+
+        t.connect((leader1 + 4) % servers);
+        t.connect((leader1 + 0) % servers);
+    }
+
+    t.one(Entry { x: 1000 }, servers, true).await;
+
+    t.end();
+}
+
+#[madsim::test]
+async fn persist3_2c() {
+    let servers = 3;
+    let t = RaftTester::new(servers).await;
+
+    info!("Test (2C): partitioned leader and one follower crash, leader restarts");
+
+    t.one(Entry { x: 101 }, 3, true).await;
+
+    let leader = t.check_one_leader().await;
+    t.disconnect((leader + 2) % servers);
+
+    t.one(Entry { x: 102 }, 2, true).await;
+
+    t.crash1((leader + 0) % servers);
+    t.crash1((leader + 1) % servers);
+    t.connect((leader + 2) % servers);
+    t.start1((leader + 0) % servers).await;
+    t.connect((leader + 0) % servers);
+
+    t.one(Entry { x: 103 }, 2, true).await;
+
+    t.start1((leader + 1) % servers).await;
+    t.connect((leader + 1) % servers);
+
+    t.one(Entry { x: 104 }, servers, true).await;
+
+    t.end();
+}
+
+#[madsim::test]
+async fn persist_long_log_2c() {
+    let servers = 3;
+    let t = RaftTester::new(servers).await;
+
+    info!("Test (2C): persistence of a log spanning many segments");
+
+    let mut random = rand::rng();
+    t.one(random.gen_entry(), servers, true).await;
+    let mut first = None;
+    for round in 0..30 {
+        let writes = t.disk_writes();
+        let leader = t.check_one_leader().await;
+        for _ in 0..200 {
+            let _ = t.start(leader, random.gen_entry()).await;
+        }
+        t.one(random.gen_entry(), servers, true).await;
+        // persisting a batch costs the same however long the log is
+        let writes = t.disk_writes() - writes;
+        let first = *first.get_or_insert(writes);
+        assert!(
+            writes <= first * 2,
+            "round {} wrote {} bytes, {} in the first round",
+            round,
+            writes,
+            first
+        );
+    }
+    let log_size = t.log_size();
+
+    // crash and re-start all
+    for i in 0..servers {
+        t.start1(i).await;
+    }
+    for i in 0..servers {
+        t.disconnect(i);
+        t.connect(i);
+    }
+    t.one(random.gen_entry(), servers, true).await;
+    assert!(
+        t.log_size() >= log_size,
+        "log shrank after restart ({} < {})",
+        t.log_size(),
+        log_size
+    );
+
+    t.end();
+}
+
+#[madsim::test]
+async fn torn_write_2c() {
+    let servers = 3;
+    let t = RaftTester::new(servers).await;
+
+    info!("Test (2C): recover from writes torn by crashes");
+
+    let mut random = rand::rng();
+    t.one(random.gen_entry(), servers, true).await;
+    let victim = (t.check_one_leader().await + 1) % servers;
+    // each restart starts a new segment after the torn one
+    for _ in 0..3 {
+        t.crash1(victim);
+        t.tear(victim).await;
+        t.start1(victim).await;
+        t.connect(victim);
+        t.one(random.gen_entry(), servers, true).await;
+    }
+    t.crash1(victim);
+    t.start1(victim).await;
+    t.connect(victim);
+    t.one(random.gen_entry(), servers, true).await;
+
+    t.end();
+}
+
+#[madsim::test]
+async fn corrupted_segment_2c() {
+    let servers = 3;
+    let t = RaftTester::new(servers).await;
+
+    info!("Test (2C): detect a corrupted record before the end of the log");
+
+    let mut random = rand::rng();
+    t.one(random.gen_entry(), servers, true).await;
+    let victim = (t.check_one_leader().await + 1) % servers;
+    // a restart starts a new segment
+    t.crash1(victim);
+    t.start1(victim).await;
+    t.connect(victim);
+    t.one(random.gen_entry(), servers, true).await;
+
+    t.crash1(victim);
+    let segment = t.corrupt_segment(victim).await;
+    match t.try_start1(victim).await {
+        Err(Error::Corrupted { file, .. }) => assert_eq!(file, segment),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(()) => panic!("corrupted {} is not detected", segment),
+    }
+    t.one(random.gen_entry(), servers - 1, true).await;
+
+    t.end();
+}
+
+#[madsim::test]
+async fn torn_state_2c() {
+    let servers = 3;
+    let t = RaftTester::new(servers).await;
+
+    info!("Test (2C): keep the vote when a crash tears saving the state");
+
+    let mut random = rand::rng();
+    t.one(random.gen_entry(), servers, true).await;
+    for _ in 0..3 {
+        let victim = (t.check_one_leader().await + 1) % servers;
+        let status = t.status(victim);
+        t.crash1(victim);
+        t.tear_state(victim).await;
+        t.start1(victim).await;
+        let restarted = t.status(victim);
+        assert_eq!(restarted.term, status.term, "term is lost");
+        assert_eq!(restarted.voted_for, status.voted_for, "vote is lost");
+        t.connect(victim);
+        t.one(random.gen_entry(), servers, true).await;
+    }
+
+    t.end();
+}
+
+/// Test the scenarios described in Figure 8 of the extended Raft paper. Each
+/// iteration asks a leader, if there is one, to insert a command in the Raft
+/// log.  If there is a leader, that leader will fail quickly with a high
+/// probability (perhaps without committing the command), or crash after a while
+/// with low probability (most likey committing the command).  If the number of
+/// alive servers isn't enough to form a majority, perhaps start a new server.
+/// The leader in a new term may try to finish replicating log entries that
+/// haven't been committed yet.
+#[madsim::test]
+async fn figure_8_2c() {
+    let servers = 5;
+    let t = RaftTester::new(servers).await;
+
+    info!("Test (2C): Figure 8");
+
+    let mut random = rand::rng();
+    t.one(random.gen_entry(), 1, true).await;
+
+    let mut nup = servers;
+    for _iters in 0..1000 {
+        let mut leader = None;
+        for i in 0..servers {
+            if t.is_started(i) && t.start(i, random.gen_entry()).await.is_ok() {
+                leader = Some(i);
+            }
+        }
+
+        let delay = if random.gen_bool(0.1) {
+            random.gen_range(Duration::from_millis(0)..RAFT_ELECTION_TIMEOUT / 2)
+        } else {
+            random.gen_range(Duration::from_millis(0)..Duration::from_millis(13))
+        };
+        time::sleep(delay).await;
+
+        if let Some(leader) = leader {
+            t.crash1(leader);
+            nup -= 1;
+        }
+
+        if nup < 3 {
+            let s = random.gen_range(0..servers);
+            if !t.is_started(s) {
+                t.start1(s).await;
+                nup += 1;
+            }
+        }
+    }
+
+    for i in 0..servers {
+        if !t.is_started(i) {
+            t.start1(i).await;
+        }
+    }
+    t.one(random.gen_entry(), servers, true).await;
+
+    t.end();
+}
+
+#[madsim::test]
+async fn unreliable_agree_2c() {
+    let servers = 5;
+
+    let t = Arc::new(RaftTester::new(servers).await);
+    info!("Test (2C): unreliable agreement");
+
+    t.set_unreliable(true);
+    let mut dones = vec![];
     for iters in 1..50 {
         for j in 0..4 {
             let x = (100 * iters) + j;
@@ -843,70 +1716,222 @@ async fn internal_churn(unreliable: bool) {
 
     let last_index = t.one(random.gen_entry(), servers, true).await;
 
+    // all servers have applied up to `last_index`, except the no-ops
     let mut really = vec![];
     for index in 1..=last_index {
-        let v = t.wait(index, servers, None).await.unwrap();
-        really.push(v.x);
+        if let (n, Some(v)) = t.n_committed(index) {
+            assert_eq!(n, servers, "index {} is not applied by all", index);
+            really.push(v.x);
+        }
+    }
+    for v1 in future::join_all(nrec).await.iter().flatten() {
+        assert!(really.contains(v1), "didn't find a value");
+    }
+
+    t.end();
+}
+
+async fn snap_common(disconnect: bool, reliable: bool, crash: bool) {
+    const MAX_LOG_SIZE: usize = 2000;
+
+    let iters = 30;
+    let servers = 3;
+    let t = RaftTester::new_with_snapshot(servers).await;
+    t.set_unreliable(!reliable);
+
+    let mut random = rand::rng();
+    t.one(random.gen_entry(), servers, true).await;
+    let mut leader1 = t.check_one_leader().await;
+
+    for i in 0..iters {
+        let mut victim = (leader1 + 1) % servers;
+        let mut sender = leader1;
+        if i % 3 == 1 {
+            sender = (leader1 + 1) % servers;
+            victim = leader1;
+        }
+
+        if disconnect {
+            t.disconnect(victim);
+            t.one(random.gen_entry(), servers - 1, true).await;
+        }
+        if crash {
+            t.crash1(victim);
+            t.one(random.gen_entry(), servers - 1, true).await;
+        }
+        // send enough to get a snapshot
+        for _ in 0..=SNAPSHOT_INTERVAL {
+            let _ = t.start(sender, random.gen_entry()).await;
+        }
+        // let applier threads catch up with the Start()'s
+        t.one(random.gen_entry(), servers - 1, true).await;
+
+        let log_size = t.log_size();
+        assert!(log_size < MAX_LOG_SIZE, "log size too large: {}", log_size);
+
+        if disconnect {
+            // reconnect a follower, who maybe behind and
+            // needs to receive a snapshot to catch up.
+            t.connect(victim);
+            t.one(random.gen_entry(), servers, true).await;
+            leader1 = t.check_one_leader().await;
+        }
+        if crash {
+            t.start1_snapshot(victim).await;
+            t.connect(victim);
+            t.one(random.gen_entry(), servers, true).await;
+            leader1 = t.check_one_leader().await;
+        }
+    }
+    t.end();
+}
+
+#[madsim::test]
+async fn snapshot_chunks_unreliable_2d() {
+    let servers = 3;
+    // every byte of snapshot in a separate chunk
+    let config = RaftConfig {
+        snapshot_chunk_size: 1,
+        ..raft_config()
+    };
+    let t = RaftTester::new_with_config(servers, true, config).await;
+    t.set_unreliable(true);
+
+    info!("Test (2D): install snapshots in chunks (unreliable)");
+
+    let mut random = rand::rng();
+    t.one(random.gen_entry(), servers, true).await;
+    for _ in 0..10 {
+        let leader = t.check_one_leader().await;
+        let victim = (leader + 1) % servers;
+        t.disconnect(victim);
+        // send enough to get a snapshot
+        for _ in 0..=SNAPSHOT_INTERVAL {
+            t.one(random.gen_entry(), servers - 1, true).await;
+        }
+        // the follower catches up from the snapshot
+        t.connect(victim);
+        t.one(random.gen_entry(), servers, true).await;
+    }
+
+    t.end();
+}
+
+#[madsim::test]
+async fn forward_proposals_snapshot_2d() {
+    let servers = 3;
+    let config = RaftConfig {
+        forward_proposals: true,
+        apply_capacity: Some(1),
+        ..raft_config()
+    };
+    let t = Arc::new(RaftTester::new_with_config(servers, true, config).await);
+
+    info!("Test (2D): forwarded proposals resolved by a snapshot");
+
+    let mut random = rand::rng();
+    t.one(random.gen_entry(), servers, true).await;
+    let leader = t.check_one_leader().await;
+    let follower = (leader + 1) % servers;
+    // the service of the follower stalls with its apply channel full
+    t.set_apply_delay(follower, Duration::from_secs(2));
+    for _ in 0..2 {
+        t.one(random.gen_entry(), servers - 1, true).await;
     }
-    for v1 in future::join_all(nrec).await.iter().flatten() {
-        assert!(really.contains(v1), "didn't find a value");
+
+    let entry = random.gen_entry();
+    let proposal = {
+        let t = t.clone();
+        task::spawn_local(async move { t.propose(follower, entry).await })
+    };
+    time::sleep(RAFT_ELECTION_TIMEOUT / 10).await;
+    // the follower catches up from a snapshot past the proposal
+    t.disconnect(follower);
+    for _ in 0..=SNAPSHOT_INTERVAL {
+        t.one(random.gen_entry(), servers - 1, true).await;
     }
+    t.connect(follower);
+    let Committed(index) = time::timeout(Duration::from_secs(1), proposal)
+        .await
+        .expect("proposal is not resolved")
+        .expect("proposal failed");
+    assert_eq!(t.n_committed(index).1, Some(entry));
+
+    t.set_apply_delay(follower, Duration::ZERO);
+    t.one(random.gen_entry(), servers, true).await;
 
     t.end();
 }
 
-async fn snap_common(disconnect: bool, reliable: bool, crash: bool) {
-    const MAX_LOG_SIZE: usize = 2000;
-
-    let iters = 30;
+#[madsim::test]
+async fn mem_storage_2d() {
     let servers = 3;
-    let t = RaftTester::new_with_snapshot(servers).await;
-    t.set_unreliable(!reliable);
+    let t = RaftTester::new_with_mem_storage(servers, true).await;
+
+    info!("Test (2D): persistence to in-memory storage");
 
     let mut random = rand::rng();
     t.one(random.gen_entry(), servers, true).await;
-    let mut leader1 = t.check_one_leader().await;
-
-    for i in 0..iters {
-        let mut victim = (leader1 + 1) % servers;
-        let mut sender = leader1;
-        if i % 3 == 1 {
-            sender = (leader1 + 1) % servers;
-            victim = leader1;
-        }
-
-        if disconnect {
-            t.disconnect(victim);
-            t.one(random.gen_entry(), servers - 1, true).await;
-        }
-        if crash {
-            t.crash1(victim);
-            t.one(random.gen_entry(), servers - 1, true).await;
-        }
+    for iter in 0..10 {
+        let victim = random.gen_range(0..servers);
+        t.crash1(victim);
         // send enough to get a snapshot
         for _ in 0..=SNAPSHOT_INTERVAL {
-            let _ = t.start(sender, random.gen_entry()).await;
+            t.one(random.gen_entry(), servers - 1, true).await;
         }
-        // let applier threads catch up with the Start()'s
-        t.one(random.gen_entry(), servers - 1, true).await;
+        if iter % 2 == 0 {
+            // crash and restart all
+            for i in 0..servers {
+                t.crash1(i);
+            }
+            for i in 0..servers {
+                t.start1_snapshot(i).await;
+                t.connect(i);
+            }
+        } else {
+            t.start1_snapshot(victim).await;
+            t.connect(victim);
+        }
+        t.one(random.gen_entry(), servers, true).await;
+    }
 
-        let log_size = t.log_size();
-        assert!(log_size < MAX_LOG_SIZE, "log size too large: {}", log_size);
+    let log_size = t.log_size();
+    assert!(log_size < 2000, "log size too large: {}", log_size);
 
-        if disconnect {
-            // reconnect a follower, who maybe behind and
-            // needs to receive a snapshot to catch up.
-            t.connect(victim);
+    t.end();
+}
+
+#[madsim::test]
+async fn corrupted_state_2d() {
+    let servers = 3;
+    let t = RaftTester::new_with_snapshot(servers).await;
+
+    info!("Test (2D): detect corrupted state and rejoin from a snapshot");
+
+    let mut random = rand::rng();
+    for file in ["state", "snapshot"] {
+        let leader = t.check_one_leader().await;
+        for _ in 0..=SNAPSHOT_INTERVAL {
             t.one(random.gen_entry(), servers, true).await;
-            leader1 = t.check_one_leader().await;
         }
-        if crash {
-            t.start1_snapshot(victim).await;
-            t.connect(victim);
-            t.one(random.gen_entry(), servers, true).await;
-            leader1 = t.check_one_leader().await;
+        let victim = (leader + 1) % servers;
+        t.crash1(victim);
+        t.corrupt(victim, file).await;
+        match t.try_start1(victim).await {
+            Err(Error::Corrupted { file: f, .. }) => assert!(f.starts_with(file), "{}", f),
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(()) => panic!("corrupted {} is not detected", file),
+        }
+
+        for _ in 0..=SNAPSHOT_INTERVAL {
+            t.one(random.gen_entry(), servers - 1, true).await;
         }
+        t.wipe(victim).await;
+        t.start1_snapshot(victim).await;
+        t.connect(victim);
+        t.one(random.gen_entry(), servers, true).await;
     }
+
     t.end();
 }
 
@@ -940,6 +1965,436 @@ async fn snapshot_install_unreliable_crash_2d() {
     snap_common(false, false, true).await;
 }
 
+#[madsim::test]
+async fn add_remove_servers_2e() {
+    let servers = 3;
+    let t = RaftTester::new_with_spares(servers, 2, false).await;
+
+    info!("Test (2E): add and remove servers");
+
+    let mut random = rand::rng();
+    t.one(random.gen_entry(), servers, true).await;
+
+    // grow to 5 servers.
+    t.add_server(3).await;
+    t.one(random.gen_entry(), 4, true).await;
+    t.add_server(4).await;
+    t.one(random.gen_entry(), 5, true).await;
+
+    // a restarted server should remember the configuration.
+    t.crash1(3);
+    t.start1(3).await;
+    t.one(random.gen_entry(), 5, true).await;
+
+    // 3 of 5 servers can make progress.
+    t.disconnect(0);
+    t.disconnect(1);
+    t.one(random.gen_entry(), 3, true).await;
+    t.connect(0);
+    t.connect(1);
+    t.one(random.gen_entry(), 5, true).await;
+
+    // remove the leader.
+    let leader = t.check_one_leader().await;
+    t.remove_server(leader).await;
+    t.one(random.gen_entry(), 4, true).await;
+    let leader2 = t.check_one_leader().await;
+    assert_ne!(leader, leader2, "removed server is still the leader");
+
+    // shrink to 2 servers, which both must be up to commit.
+    let mut members = t.members();
+    members.truncate(2);
+    t.change_membership(members.clone()).await;
+    t.one(random.gen_entry(), 2, true).await;
+    t.disconnect(members[0]);
+    time::sleep(RAFT_ELECTION_TIMEOUT).await;
+    let max_index = t.max_committed();
+    for &i in &members {
+        let _ = t.start(i, random.gen_entry()).await;
+    }
+    time::sleep(RAFT_ELECTION_TIMEOUT).await;
+    assert_eq!(
+        t.max_committed(),
+        max_index,
+        "committed without a majority of the new configuration"
+    );
+    t.connect(members[0]);
+    t.one(random.gen_entry(), 2, true).await;
+
+    // the cluster can not be emptied.
+    let leader = t.check_one_leader().await;
+    assert!(matches!(
+        t.try_change_membership(leader, vec![]).await,
+        Err(Error::NoVoters)
+    ));
+    t.one(random.gen_entry(), 2, true).await;
+
+    t.end();
+}
+
+#[madsim::test]
+async fn single_server_change_2e() {
+    let servers = 3;
+    let t = Arc::new(RaftTester::new_with_spares(servers, 2, true).await);
+
+    info!("Test (2E): single-server membership changes");
+
+    let mut random = rand::rng();
+    for _ in 0..3 * SNAPSHOT_INTERVAL {
+        t.one(random.gen_entry(), servers, true).await;
+    }
+
+    // a new server catches up before it is added.
+    t.add_server1(3).await;
+    t.one(random.gen_entry(), 4, true).await;
+
+    // a server that is down never catches up, and no other change can be
+    // made meanwhile.
+    let leader = t.check_one_leader().await;
+    let t1 = t.clone();
+    let adding = task::spawn(async move { t1.try_add_server1(leader, 4).await });
+    time::sleep(Duration::from_millis(100)).await;
+    assert!(matches!(
+        t.try_remove_server1(leader, 3).await,
+        Err(Error::ChangePending)
+    ));
+    assert!(matches!(adding.await, Err(Error::CatchUpFailed(_))));
+    t.one(random.gen_entry(), 4, true).await;
+
+    // once up, it is added.
+    t.add_server1(4).await;
+    t.one(random.gen_entry(), 5, true).await;
+
+    // a removed leader steps down.
+    let leader = t.check_one_leader().await;
+    t.remove_server1(leader).await;
+    t.one(random.gen_entry(), 4, true).await;
+    let leader2 = t.check_one_leader().await;
+    assert_ne!(leader, leader2, "removed server is still the leader");
+    assert!(!t.is_leader(leader), "removed server is still the leader");
+
+    t.end();
+}
+
+#[madsim::test]
+async fn remove_last_voter_2e() {
+    let t = RaftTester::new(1).await;
+
+    info!("Test (2E): the last voter can not be removed");
+
+    let mut random = rand::rng();
+    t.one(random.gen_entry(), 1, true).await;
+    assert!(matches!(
+        t.try_remove_server1(0, 0).await,
+        Err(Error::NoVoters)
+    ));
+    assert!(t.is_leader(0), "the leader is gone");
+    t.one(random.gen_entry(), 1, true).await;
+
+    t.end();
+}
+
+#[madsim::test]
+async fn single_server_change_pending_2e() {
+    let servers = 3;
+    let t = Arc::new(RaftTester::new_with_spares(servers, 2, false).await);
+
+    info!("Test (2E): one single-server change at a time");
+
+    let mut random = rand::rng();
+    t.one(random.gen_entry(), servers, true).await;
+    t.add_learner(3).await;
+    t.one(random.gen_entry(), servers, true).await;
+
+    // promoting a learner waits for a server being caught up.
+    let leader = t.check_one_leader().await;
+    let t1 = t.clone();
+    let adding = task::spawn(async move { t1.try_add_server1(leader, 4).await });
+    time::sleep(Duration::from_millis(100)).await;
+    assert!(matches!(
+        t.try_add_server1(leader, 3).await,
+        Err(Error::ChangePending)
+    ));
+    assert!(matches!(adding.await, Err(Error::CatchUpFailed(_))));
+
+    // and goes ahead afterwards.
+    t.add_server1(3).await;
+    t.one(random.gen_entry(), 4, true).await;
+
+    t.end();
+}
+
+#[madsim::test]
+async fn single_server_change_cancel_2e() {
+    let servers = 3;
+    let t = RaftTester::new_with_spares(servers, 2, false).await;
+
+    info!("Test (2E): cancelled single-server change");
+
+    let mut random = rand::rng();
+    t.one(random.gen_entry(), servers, true).await;
+
+    // drop the change while a server that is down is being caught up.
+    let leader = t.check_one_leader().await;
+    let res = time::timeout(Duration::from_millis(100), t.try_add_server1(leader, 4)).await;
+    assert!(res.is_err(), "a server that is down was added");
+
+    // another change can be made at once.
+    t.start1(3).await;
+    t.connect(3);
+    t.try_add_server1(leader, 3)
+        .await
+        .expect("change rejected after the previous one was dropped");
+    t.one(random.gen_entry(), 4, true).await;
+
+    t.end();
+}
+
+#[madsim::test]
+async fn witness_2e() {
+    let servers = 3;
+    let witness = servers - 1;
+    let t = RaftTester::new_with_witness(servers, false).await;
+
+    info!("Test (2E): witness");
+
+    // the witness counts toward commit, but applies nothing.
+    let mut random = rand::rng();
+    for _ in 0..20 * SNAPSHOT_INTERVAL {
+        t.one(random.gen_entry(), servers - 1, true).await;
+    }
+    assert_eq!(t.applied(witness), 0, "witness applied entries");
+
+    // the witness is never elected, and one full server commits with it.
+    for _ in 0..3 {
+        let leader = t.check_one_leader().await;
+        assert_ne!(leader, witness, "witness became the leader");
+        t.disconnect(leader);
+        let leader2 = t.check_one_leader().await;
+        assert_ne!(leader2, witness, "witness became the leader");
+        t.one(random.gen_entry(), 1, true).await;
+        t.connect(leader);
+        t.one(random.gen_entry(), servers - 1, true).await;
+    }
+
+    // without full servers there is no leader.
+    for i in 0..servers - 1 {
+        t.disconnect(i);
+    }
+    time::sleep(2 * RAFT_ELECTION_TIMEOUT).await;
+    t.check_no_leader();
+    for i in 0..servers - 1 {
+        t.connect(i);
+    }
+    t.one(random.gen_entry(), servers - 1, true).await;
+
+    // the witness keeps a short log without commands.
+    let size = t.disk_size(witness);
+    for i in 0..servers - 1 {
+        assert!(
+            size * 4 < t.disk_size(i),
+            "witness uses {} bytes but server {} uses {}",
+            size,
+            i,
+            t.disk_size(i)
+        );
+    }
+
+    t.end();
+}
+
+#[madsim::test]
+async fn membership_churn_2e() {
+    let servers = 3;
+    let spares = 3;
+    let t = Arc::new(RaftTester::new_with_spares(servers, spares, false).await);
+    t.set_unreliable(true);
+
+    info!("Test (2E): membership changes while committing");
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let client = task::spawn({
+        let t = t.clone();
+        let stop = stop.clone();
+        async move {
+            let mut random = rand::rng();
+            let mut n = 0;
+            while !stop.load(Ordering::Relaxed) {
+                t.one(random.gen_entry(), 2, true).await;
+                n += 1;
+            }
+            n
+        }
+    });
+
+    let mut random = rand::rng();
+    for _ in 0..10 {
+        let members = t.members();
+        let others = (0..servers + spares)
+            .filter(|i| !members.contains(i))
+            .collect::<Vec<_>>();
+        if others.is_empty() || (members.len() > 3 && random.gen_bool(0.5)) {
+            let i = members[random.gen_range(0..members.len())];
+            t.remove_server(i).await;
+        } else {
+            let i = others[random.gen_range(0..others.len())];
+            t.add_server(i).await;
+        }
+        time::sleep(Duration::from_millis(random.gen_range(0..500))).await;
+    }
+
+    stop.store(true, Ordering::Relaxed);
+    let n = client.await;
+    assert!(n > 0, "no commands were committed");
+
+    t.set_unreliable(false);
+    let members = t.members();
+    t.one(random.gen_entry(), members.len(), true).await;
+
+    t.end();
+}
+
+#[madsim::test]
+async fn transfer_leadership_2e() {
+    let servers = 5;
+    let t = RaftTester::new(servers).await;
+
+    info!("Test (2E): leadership transfer");
+
+    let mut random = rand::rng();
+    t.one(random.gen_entry(), servers, true).await;
+
+    // transfer to a lagging follower.
+    let leader1 = t.check_one_leader().await;
+    let term1 = t.check_terms();
+    let target = (leader1 + 1) % servers;
+    t.disconnect(target);
+    for _ in 0..5 {
+        t.one(random.gen_entry(), servers - 1, true).await;
+    }
+    t.connect(target);
+    t.transfer_leadership(leader1, target).await.unwrap();
+    assert!(
+        t.start(leader1, random.gen_entry()).await.is_err(),
+        "old leader accepted a proposal"
+    );
+    assert_eq!(
+        t.check_one_leader().await,
+        target,
+        "target is not the leader"
+    );
+    assert_eq!(t.check_terms(), term1 + 1, "more than one election");
+    t.one(random.gen_entry(), servers, true).await;
+
+    // a transfer to an unreachable follower is aborted.
+    let leader2 = target;
+    let target = (leader2 + 1) % servers;
+    t.disconnect(target);
+    let res = t.transfer_leadership(leader2, target).await;
+    assert!(
+        matches!(res, Err(Error::TransferAborted)),
+        "transfer to a disconnected follower: {:?}",
+        res
+    );
+    t.one(random.gen_entry(), servers - 1, true).await;
+    assert_eq!(t.check_one_leader().await, leader2, "leader changed");
+    t.connect(target);
+
+    // rolling restart.
+    for _ in 0..servers {
+        let leader = t.check_one_leader().await;
+        let target = (leader + 1) % servers;
+        t.transfer_leadership(leader, target).await.unwrap();
+        t.crash1(leader);
+        t.one(random.gen_entry(), servers - 1, true).await;
+        t.start1(leader).await;
+        t.one(random.gen_entry(), servers, true).await;
+    }
+
+    t.end();
+}
+
+#[madsim::test]
+async fn transfer_leadership_cancel_2e() {
+    let servers = 3;
+    let t = RaftTester::new(servers).await;
+
+    info!("Test (2E): cancelled leadership transfer");
+
+    let mut random = rand::rng();
+    t.one(random.gen_entry(), servers, true).await;
+
+    // drop a transfer to an unreachable follower before it is aborted.
+    let leader = t.check_one_leader().await;
+    let target = (leader + 1) % servers;
+    t.disconnect(target);
+    let res = time::timeout(
+        Duration::from_millis(50),
+        t.transfer_leadership(leader, target),
+    )
+    .await;
+    assert!(res.is_err(), "transfer to a disconnected follower finished");
+
+    // the leader accepts proposals again at once.
+    t.start(leader, random.gen_entry())
+        .await
+        .expect("leader rejected a proposal after the transfer was dropped");
+    t.one(random.gen_entry(), servers - 1, true).await;
+    assert_eq!(t.check_one_leader().await, leader, "leader changed");
+    t.connect(target);
+    t.one(random.gen_entry(), servers, true).await;
+
+    t.end();
+}
+
+#[madsim::test]
+async fn learner_2e() {
+    let servers = 3;
+    let t = RaftTester::new_with_spares(servers, 2, true).await;
+
+    info!("Test (2E): learners catch up without voting");
+
+    let mut random = rand::rng();
+    for _ in 0..3 * SNAPSHOT_INTERVAL {
+        t.one(random.gen_entry(), servers, true).await;
+    }
+
+    // learners catch up from the snapshot.
+    t.add_learner(3).await;
+    t.add_learner(4).await;
+    t.one(random.gen_entry(), servers + 2, true).await;
+
+    // the leader and two learners are not a quorum.
+    let leader = t.check_one_leader().await;
+    assert!(leader < servers, "learner {} became the leader", leader);
+    t.disconnect((leader + 1) % servers);
+    t.disconnect((leader + 2) % servers);
+    let index = t.start(leader, random.gen_entry()).await.unwrap().index;
+    time::sleep(2 * RAFT_ELECTION_TIMEOUT).await;
+    let (nd, _) = t.n_committed(index);
+    assert_eq!(nd, 0, "committed without a quorum of voters");
+
+    // learners never start an election.
+    t.disconnect(leader);
+    time::sleep(2 * RAFT_ELECTION_TIMEOUT).await;
+    t.check_no_leader();
+    for i in 0..servers {
+        t.connect(i);
+    }
+    t.one(random.gen_entry(), servers + 2, true).await;
+
+    // a promoted learner votes and counts toward quorum.
+    t.promote_learner(3).await;
+    let leader = t.check_one_leader().await;
+    let victim = (leader + 1) % servers;
+    t.disconnect(victim);
+    t.one(random.gen_entry(), servers + 1, true).await;
+    t.connect(victim);
+    t.one(random.gen_entry(), servers + 2, true).await;
+
+    t.end();
+}
+
 trait GenEntry {
     fn gen_entry(&mut self) -> Entry;
 }
@@ -0,0 +1,209 @@
+//! A typed layer over [`RaftHandle`](super::RaftHandle), which encodes
+//! commands with bincode so that services deal with their own command type.
+
+use super::raft::{
+    self as raw, Committed, PersistStats, RaftConfig, Result, RoleRecver, Start, Status,
+};
+use super::storage::Storage;
+use futures::{stream, StreamExt};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{marker::PhantomData, net::SocketAddr};
+
+/// A committed message with the command decoded.
+///
+/// See [`raw::ApplyMsg`].
+pub enum ApplyMsg<C> {
+    Command {
+        cmd: C,
+        index: u64,
+    },
+    Commands {
+        entries: Vec<(u64, C)>,
+    },
+    Snapshot {
+        data: Vec<u8>,
+        term: u64,
+        index: u64,
+    },
+    Config {
+        peers: Vec<SocketAddr>,
+        index: u64,
+    },
+}
+
+impl<C: DeserializeOwned> ApplyMsg<C> {
+    fn decode(msg: raw::ApplyMsg) -> Self {
+        match msg {
+            raw::ApplyMsg::Command { data, index } => ApplyMsg::Command {
+                cmd: decode(&data),
+                index,
+            },
+            raw::ApplyMsg::Commands { entries } => ApplyMsg::Commands {
+                entries: (entries.into_iter())
+                    .map(|(index, data)| (index, decode(&data)))
+                    .collect(),
+            },
+            raw::ApplyMsg::Snapshot { data, term, index } => {
+                ApplyMsg::Snapshot { data, term, index }
+            }
+            raw::ApplyMsg::Config { peers, index } => ApplyMsg::Config { peers, index },
+        }
+    }
+}
+
+fn decode<C: DeserializeOwned>(data: &[u8]) -> C {
+    // all commands are encoded from `C` by `RaftHandle`, which does not
+    // expose the raw handle
+    bincode::deserialize(data).expect("invalid command")
+}
+
+pub type MsgRecver<C> = stream::Map<raw::MsgRecver, fn(raw::ApplyMsg) -> ApplyMsg<C>>;
+
+/// A Raft peer replicating commands of type `C`.
+///
+/// Other than proposing commands, it has the methods of the raw
+/// [`raw::RaftHandle`]. The raw handle is not exposed, so that all commands
+/// are proposed as `C` and decode in [`MsgRecver`].
+pub struct RaftHandle<C> {
+    raw: raw::RaftHandle,
+    _cmd: PhantomData<fn(C)>,
+}
+
+impl<C> Clone for RaftHandle<C> {
+    fn clone(&self) -> Self {
+        RaftHandle {
+            raw: self.raw.clone(),
+            _cmd: PhantomData,
+        }
+    }
+}
+
+impl<C: Serialize + DeserializeOwned + 'static> RaftHandle<C> {
+    /// See [`raw::RaftHandle::new`].
+    pub async fn new(
+        peers: Vec<SocketAddr>,
+        me: usize,
+        raft_config: RaftConfig,
+        storage: impl Storage,
+    ) -> Result<(Self, MsgRecver<C>)> {
+        let (raw, recver) = raw::RaftHandle::new(peers, me, raft_config, storage).await?;
+        Ok(Self::wrap(raw, recver))
+    }
+
+    /// See [`raw::RaftHandle::join`].
+    pub async fn join(
+        addr: SocketAddr,
+        raft_config: RaftConfig,
+        storage: impl Storage,
+    ) -> Result<(Self, MsgRecver<C>)> {
+        let (raw, recver) = raw::RaftHandle::join(addr, raft_config, storage).await?;
+        Ok(Self::wrap(raw, recver))
+    }
+
+    fn wrap(raw: raw::RaftHandle, recver: raw::MsgRecver) -> (Self, MsgRecver<C>) {
+        let handle = RaftHandle {
+            raw,
+            _cmd: PhantomData,
+        };
+        (handle, recver.map(ApplyMsg::decode as fn(_) -> _))
+    }
+
+    /// See [`raw::RaftHandle::start`].
+    pub async fn start(&self, cmd: &C) -> Result<Start> {
+        self.raw.start(&bincode::serialize(cmd).unwrap()).await
+    }
+
+    /// See [`raw::RaftHandle::propose`].
+    pub async fn propose(&self, cmd: &C) -> Result<Committed> {
+        self.raw.propose(&bincode::serialize(cmd).unwrap()).await
+    }
+}
+
+impl<C> RaftHandle<C> {
+    /// See [`raw::RaftHandle::term`].
+    pub fn term(&self) -> u64 {
+        self.raw.term()
+    }
+
+    /// See [`raw::RaftHandle::is_leader`].
+    pub fn is_leader(&self) -> bool {
+        self.raw.is_leader()
+    }
+
+    /// See [`raw::RaftHandle::leader`].
+    pub fn leader(&self) -> Option<usize> {
+        self.raw.leader()
+    }
+
+    /// See [`raw::RaftHandle::watch_role`].
+    pub fn watch_role(&self) -> RoleRecver {
+        self.raw.watch_role()
+    }
+
+    /// See [`raw::RaftHandle::status`].
+    pub fn status(&self) -> Status {
+        self.raw.status()
+    }
+
+    /// See [`raw::RaftHandle::persist_stats`].
+    pub fn persist_stats(&self) -> PersistStats {
+        self.raw.persist_stats()
+    }
+
+    /// See [`raw::RaftHandle::state_size`].
+    pub fn state_size(&self) -> usize {
+        self.raw.state_size()
+    }
+
+    /// See [`raw::RaftHandle::read_index`].
+    pub async fn read_index(&self) -> Result<u64> {
+        self.raw.read_index().await
+    }
+
+    /// See [`raw::RaftHandle::change_membership`].
+    pub async fn change_membership(&self, peers: Vec<SocketAddr>) -> Result<()> {
+        self.raw.change_membership(peers).await
+    }
+
+    /// See [`raw::RaftHandle::add_learner`].
+    pub async fn add_learner(&self, addr: SocketAddr) -> Result<()> {
+        self.raw.add_learner(addr).await
+    }
+
+    /// See [`raw::RaftHandle::promote_learner`].
+    pub async fn promote_learner(&self, addr: SocketAddr) -> Result<()> {
+        self.raw.promote_learner(addr).await
+    }
+
+    /// See [`raw::RaftHandle::add_server`].
+    pub async fn add_server(&self, addr: SocketAddr) -> Result<()> {
+        self.raw.add_server(addr).await
+    }
+
+    /// See [`raw::RaftHandle::remove_server`].
+    pub async fn remove_server(&self, addr: SocketAddr) -> Result<()> {
+        self.raw.remove_server(addr).await
+    }
+
+    /// See [`raw::RaftHandle::transfer_leadership`].
+    pub async fn transfer_leadership(&self, target: SocketAddr) -> Result<()> {
+        self.raw.transfer_leadership(target).await
+    }
+
+    /// See [`raw::RaftHandle::cond_install_snapshot`].
+    pub async fn cond_install_snapshot(
+        &self,
+        last_included_term: u64,
+        last_included_index: u64,
+        snapshot: &[u8],
+    ) -> bool {
+        (self.raw)
+            .cond_install_snapshot(last_included_term, last_included_index, snapshot)
+            .await
+    }
+
+    /// See [`raw::RaftHandle::snapshot`].
+    pub async fn snapshot(&self, index: u64, snapshot: &[u8]) -> Result<()> {
+        self.raw.snapshot(index, snapshot).await
+    }
+}
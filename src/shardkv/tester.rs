@@ -1,4 +1,5 @@
 use super::{client::Clerk, server::ShardKvServer};
+use crate::raft::FsStorage;
 use crate::shard_ctrler::{client::Clerk as CtrlerClerk, server::ShardCtrler, N_SHARDS};
 use ::rand::distributions::Alphanumeric;
 use madsim::{
@@ -91,8 +92,8 @@ impl Tester {
     pub fn check_logs(&self) {
         for group in self.groups.iter() {
             for &addr in group.addrs.iter() {
-                let state_size = self.handle.fs.get_file_size(addr, "state").unwrap_or(0);
-                let snap_size = self.handle.fs.get_file_size(addr, "snapshot").unwrap_or(0);
+                let state_size = FsStorage::disk_size(&self.handle.fs, addr);
+                let snap_size = FsStorage::snapshot_size(&self.handle.fs, addr);
                 if let Some(limit) = self.max_raft_state {
                     assert!(
                         state_size as usize <= 8 * limit,
@@ -114,8 +115,8 @@ impl Tester {
         let mut size = 0;
         for group in self.groups.iter() {
             for &addr in group.addrs.iter() {
-                let state_size = self.handle.fs.get_file_size(addr, "state").unwrap();
-                let snap_size = self.handle.fs.get_file_size(addr, "snapshot").unwrap();
+                let state_size = FsStorage::disk_size(&self.handle.fs, addr);
+                let snap_size = FsStorage::snapshot_size(&self.handle.fs, addr);
                 size += state_size + snap_size;
             }
         }